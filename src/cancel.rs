@@ -0,0 +1,73 @@
+// Long operations take a token they check every so often, and give up once it is cancelled or
+// its deadline has passed. Checking is a relaxed load and, for tokens with a deadline, a read of
+// the clock, so it is cheap enough to do every few hundred items of work.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Tells an operation to stop early. Clones share the same state, so one can be handed to the
+/// operation and another kept to cancel it with.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+impl CancellationToken {
+    /// A token that is only cancelled by calling `cancel`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A token that is cancelled once `deadline` passes, or by calling `cancel` before then
+    pub fn with_deadline(deadline: Instant) -> Self {
+        Self {
+            cancelled: Arc::default(),
+            deadline: Some(deadline),
+        }
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Cancel the operations using this token or any of its clones
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.check().is_err()
+    }
+
+    /// Fail if the token has been cancelled or its deadline has passed. A token that has been
+    /// cancelled reports that even if its deadline has also passed.
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(Cancelled::Requested);
+        }
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(Cancelled::DeadlinePassed),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Why an operation stopped early
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cancelled {
+    /// `cancel` was called on the token
+    Requested,
+    /// The token's deadline passed
+    DeadlinePassed,
+}
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cancelled::Requested => f.write_str("the operation was cancelled"),
+            Cancelled::DeadlinePassed => f.write_str("the operation's deadline passed"),
+        }
+    }
+}
+impl std::error::Error for Cancelled {}
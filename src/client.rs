@@ -1,11 +1,19 @@
 use crate::message::*;
+use crate::transport::{Address, Stream};
 use std::default::Default;
-use std::io::{Read, Write};
-use std::net::{SocketAddr, TcpStream};
+use std::io::{BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 
 /// A client for interacting with the server at address `address`
 pub struct Client {
-    address: SocketAddr,
+    address: Address,
+    /// The wire format used to talk to the server
+    codec: &'static dyn Codec,
+    /// The token sent with admin requests
+    admin_token: Option<String>,
+    /// How long the server may take to answer each request
+    timeout: Option<Duration>,
 }
 impl Default for Client {
     fn default() -> Self {
@@ -13,14 +21,67 @@ impl Default for Client {
     }
 }
 
+impl From<Address> for Client {
+    /// Create a client that will connect to the server at `address`
+    fn from(address: Address) -> Self {
+        Client {
+            address,
+            codec: &BinaryCodec,
+            admin_token: None,
+            timeout: None,
+        }
+    }
+}
+impl From<SocketAddr> for Client {
+    /// Create a client that will connect to the server at `address`, e.g. the address reported
+    /// by `Server::local_addr`
+    fn from(address: SocketAddr) -> Self {
+        Self::from(Address::Tcp(address))
+    }
+}
+
 impl Client {
     // TODO:
     // Create a client that will connect to the server at `address` and `port`. You can create a
     // SocketAddr from an IpAddr and a port with `SocketAddr::new(addr, port)`.
     // You can create an IpAddr from a string with `address.parse().unwrap()`.
+    //
+    // An `address` of the form `unix:/path/to/socket` connects to a server's Unix domain socket
+    // instead, in which case `port` is ignored.
     pub fn new(address: &str, port: u16) -> Self {
-        let addr: SocketAddr = format!("{}:{}", address, port).parse().unwrap();
-        Client { address: addr }
+        if address.starts_with("unix:") {
+            return Self::from(address.parse::<Address>().unwrap());
+        }
+        let ip: IpAddr = address.parse().unwrap();
+        Self::from(SocketAddr::new(ip, port))
+    }
+
+    /// Talk to the server using `codec` instead of the default binary format
+    pub fn with_codec(mut self, codec: &'static dyn Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Send `token` with admin requests, as the server requires for everything but health and
+    /// readiness checks
+    pub fn with_admin_token<S: Into<String>>(mut self, token: S) -> Self {
+        self.admin_token = Some(token.into());
+        self
+    }
+
+    /// Ask the server to give up on each request that it can't answer within `timeout`, in which
+    /// case it answers with a `Timeout` error
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    // Encode `request`, with the client's deadline if it has one
+    fn encode(&self, request: Request) -> Vec<u8> {
+        match self.timeout {
+            Some(timeout) => self.codec.encode_request(&request.with_timeout(timeout)),
+            None => self.codec.encode_request(&request),
+        }
     }
 
     // TODO:
@@ -32,11 +93,11 @@ impl Client {
     // You can write to the stream with `stream.write_all(&bytes)`.
     // You can read from the stream by calling your `Response::from_bytes` function, since
     // `TcpStream` implements `Read`.
-    fn send(&self, request: &Request) -> Option<Response> {
-        let mut stream = TcpStream::connect(self.address).unwrap();
+    fn send(&self, request: Request) -> Option<Response> {
+        let mut stream = Stream::connect(&self.address).unwrap();
 
         // Serialize request
-        let bytes = request.to_bytes();
+        let bytes = self.encode(request);
 
         // Send the request bytes
         stream.write_all(&bytes).unwrap();
@@ -46,7 +107,9 @@ impl Client {
         stream.read_to_end(&mut buffer).unwrap();
 
         // Deserialize and return the response
-        Response::from_bytes(&mut std::io::Cursor::new(buffer))
+        self.codec
+            .decode_response(&mut std::io::Cursor::new(buffer))
+            .ok()
     }
 
     // TODO:
@@ -57,20 +120,102 @@ impl Client {
     pub fn publish_from_path(&self, path: &str) -> Option<Response> {
         let content = std::fs::read_to_string(path).unwrap();
         let request = Request::Publish { doc: content};
-        self.send(&request)
+        self.send(request)
     }
     // TODO:
     // Send a `Search` request to the server with the given `word`. Return the response from the
     // server.
     pub fn search(&self, word: &str) -> Option<Response> {
         let request = Request::Search { word: word.to_string() };
-        self.send(&request)
+        self.send(request)
     }
     // TODO:
     // Send a `Retrieve` request to the server with the given `id`. Return the response from the
     // server.
-    pub fn retrieve(&self, id: usize) -> Option<Response> {
-        let request = Request::Retrieve { id };
-        self.send(&request)
+    pub fn retrieve(&self, id: DocId) -> Option<Response> {
+        let request = Request::retrieve(id);
+        self.send(request)
+    }
+
+    /// Send a `Retrieve` request for up to `len` bytes of the document with the given `id`,
+    /// starting at byte `offset`. Return the response from the server.
+    pub fn retrieve_range(&self, id: DocId, offset: u64, len: Option<u64>) -> Option<Response> {
+        let request = Request::Retrieve { id, offset, len };
+        self.send(request)
+    }
+
+    /// Ask the server for its statistics. Return the response from the server.
+    pub fn stats(&self) -> Option<Response> {
+        self.send(Request::Stats)
+    }
+
+    /// Ask the server for up to `limit` of its most recent slow requests. Return the response from
+    /// the server.
+    pub fn slow_queries(&self, limit: u32) -> Option<Response> {
+        self.admin(AdminCommand::SlowQueries { limit })
+    }
+
+    /// Check that the server is up. Return the response from the server.
+    pub fn health(&self) -> Option<Response> {
+        self.admin(AdminCommand::Health)
+    }
+
+    /// Ask the server whether it is ready for traffic. Return the response from the server.
+    pub fn ready(&self) -> Option<Response> {
+        self.admin(AdminCommand::Ready)
+    }
+
+    /// Tell the server to stop, waiting for requests in flight if `graceful`. Return the response
+    /// from the server.
+    pub fn shutdown(&self, graceful: bool) -> Option<Response> {
+        self.admin(AdminCommand::Shutdown { graceful })
+    }
+
+    /// Tell the server to write out anything it has buffered. Return the response from the
+    /// server.
+    pub fn flush(&self) -> Option<Response> {
+        self.admin(AdminCommand::Flush)
+    }
+
+    fn admin(&self, command: AdminCommand) -> Option<Response> {
+        self.send(Request::Admin {
+            token: self.admin_token.clone(),
+            command,
+        })
+    }
+
+    /// Stream the document with the given `id` from byte `offset` onwards into `writer`,
+    /// `chunk_size` bytes at a time. Each chunk is written out as soon as it arrives, so the
+    /// document is never held in memory as a whole. Return the number of bytes written, or `None`
+    /// if the server reported a failure or the stream was cut short.
+    pub fn retrieve_to_writer<W: Write>(
+        &self,
+        id: DocId,
+        offset: u64,
+        chunk_size: u32,
+        mut writer: W,
+    ) -> Option<u64> {
+        let mut stream = Stream::connect(&self.address).ok()?;
+        let request = Request::RetrieveStream {
+            id,
+            offset,
+            chunk_size,
+        };
+        stream.write_all(&self.encode(request)).ok()?;
+
+        let mut reader = BufReader::new(stream);
+        let mut written = 0;
+        loop {
+            match self.codec.decode_response(&mut reader).ok()? {
+                Response::RetrieveChunk(chunk) => {
+                    writer.write_all(chunk.as_bytes()).ok()?;
+                    written += chunk.len() as u64;
+                }
+                Response::RetrieveEnd => break,
+                _ => return None,
+            }
+        }
+        writer.flush().ok()?;
+        Some(written)
     }
 }
@@ -0,0 +1,403 @@
+use crate::database::{Analyzer, DEFAULT_BUCKETS};
+use crate::server::DEFAULT_WORKERS;
+use serde::Deserialize;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The port the server listens on unless configured otherwise
+pub const DEFAULT_PORT: u16 = 7878;
+/// How many connections may wait for a worker unless configured otherwise
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
+/// Everything that can be tuned about a server.
+///
+/// Build one with [`ServerConfig::builder`], optionally starting from a TOML file:
+///
+/// ```toml
+/// workers = 8
+/// max_workers = 32
+/// worker_idle_timeout_ms = 60000
+/// queue_capacity = 512
+/// buckets = 256
+/// bind = "0.0.0.0"
+/// port = 7878
+/// http_port = 8080
+/// unix_socket = "/run/ngram.sock"
+/// data_dir = "data"
+/// shutdown_timeout_ms = 5000
+/// admin_token = "change me"
+///
+/// [limits]
+/// max_document_bytes = 104857600
+/// idle_timeout_ms = 10000
+/// read_timeout_ms = 30000
+/// write_timeout_ms = 30000
+/// max_connections = 1024
+///
+/// [slow_query_log]
+/// threshold_ms = 250
+/// path = "slow-queries.log"
+/// keep = 100
+///
+/// [analyzer]
+/// lowercase = true
+/// strip_punctuation = true
+/// ```
+///
+/// Every key is optional and falls back to its default.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// The number of threads handling requests
+    pub workers: usize,
+    /// If set, the thread pool grows up to this many threads while connections are waiting for
+    /// a worker, and shrinks back to `workers` when they are no longer needed
+    pub max_workers: Option<usize>,
+    /// How long a thread beyond `workers` may sit idle before it exits, in milliseconds
+    pub worker_idle_timeout_ms: u64,
+    /// How many accepted connections may wait for a free worker. Connections beyond that are
+    /// answered straight away with an overloaded error.
+    pub queue_capacity: usize,
+    /// The number of buckets in the database's reverse index
+    pub buckets: usize,
+    /// The address to listen on
+    pub bind: IpAddr,
+    /// The port to listen on, or 0 to have the OS pick one
+    pub port: u16,
+    /// The port to serve the HTTP/REST gateway on, if any
+    pub http_port: Option<u16>,
+    /// The path of a Unix domain socket to also listen on, if any
+    pub unix_socket: Option<PathBuf>,
+    /// A directory whose files are all published when the server starts
+    pub data_dir: Option<PathBuf>,
+    /// How long to wait for in-flight requests when shutting down, in milliseconds
+    pub shutdown_timeout_ms: u64,
+    /// The token admin requests must carry. Without one, only health and readiness checks are
+    /// accepted.
+    pub admin_token: Option<String>,
+    pub limits: Limits,
+    pub slow_query_log: SlowQueryLogConfig,
+    pub analyzer: Analyzer,
+}
+
+/// Where and when requests are recorded as slow
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SlowQueryLogConfig {
+    /// Requests that take at least this long are recorded, in milliseconds
+    pub threshold_ms: u64,
+    /// A file to append each slow request to as a line of JSON, if any
+    pub path: Option<PathBuf>,
+    /// How many of the most recent slow requests to keep for `SlowQueries` admin requests
+    pub keep: usize,
+}
+impl SlowQueryLogConfig {
+    pub fn threshold(&self) -> Duration {
+        Duration::from_millis(self.threshold_ms)
+    }
+}
+impl Default for SlowQueryLogConfig {
+    fn default() -> Self {
+        Self {
+            threshold_ms: 500,
+            path: None,
+            keep: 100,
+        }
+    }
+}
+
+/// Limits on what clients may ask of the server. Timeouts are in milliseconds, and a timeout of
+/// 0 disables it.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// The largest document that may be published, in bytes
+    pub max_document_bytes: u64,
+    /// How long a new connection may take to start sending its request
+    pub idle_timeout_ms: u64,
+    /// How long any single read may wait once a request has started arriving
+    pub read_timeout_ms: u64,
+    /// How long any single write of a response may wait for the client to make room
+    pub write_timeout_ms: u64,
+    /// The most connections handled or queued at once; further connections are told the server
+    /// is overloaded and closed immediately
+    pub max_connections: usize,
+}
+impl Limits {
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        timeout_from_ms(self.idle_timeout_ms)
+    }
+    pub fn read_timeout(&self) -> Option<Duration> {
+        timeout_from_ms(self.read_timeout_ms)
+    }
+    pub fn write_timeout(&self) -> Option<Duration> {
+        timeout_from_ms(self.write_timeout_ms)
+    }
+}
+
+fn timeout_from_ms(ms: u64) -> Option<Duration> {
+    (ms > 0).then(|| Duration::from_millis(ms))
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            workers: DEFAULT_WORKERS,
+            max_workers: None,
+            worker_idle_timeout_ms: 60_000,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            buckets: DEFAULT_BUCKETS,
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: DEFAULT_PORT,
+            http_port: None,
+            unix_socket: None,
+            data_dir: None,
+            shutdown_timeout_ms: 10_000,
+            admin_token: None,
+            limits: Limits::default(),
+            slow_query_log: SlowQueryLogConfig::default(),
+            analyzer: Analyzer::default(),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_document_bytes: u32::MAX as u64,
+            idle_timeout_ms: 10_000,
+            read_timeout_ms: 30_000,
+            write_timeout_ms: 30_000,
+            max_connections: 1024,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Start building a configuration from the defaults
+    pub fn builder() -> ServerConfigBuilder {
+        ServerConfigBuilder {
+            config: ServerConfig::default(),
+        }
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_millis(self.shutdown_timeout_ms)
+    }
+
+    pub fn worker_idle_timeout(&self) -> Duration {
+        Duration::from_millis(self.worker_idle_timeout_ms)
+    }
+
+    /// Check that the configuration describes a server that can actually run
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.workers == 0 {
+            return Err(ConfigError::Invalid("workers must be at least 1".into()));
+        }
+        if let Some(max_workers) = self.max_workers {
+            if max_workers < self.workers {
+                return Err(ConfigError::Invalid(format!(
+                    "max_workers ({}) is less than workers ({})",
+                    max_workers, self.workers
+                )));
+            }
+        }
+        if self.worker_idle_timeout_ms == 0 {
+            return Err(ConfigError::Invalid(
+                "worker_idle_timeout_ms must be at least 1".into(),
+            ));
+        }
+        if self.queue_capacity == 0 {
+            return Err(ConfigError::Invalid("queue_capacity must be at least 1".into()));
+        }
+        if self.buckets == 0 {
+            return Err(ConfigError::Invalid("buckets must be at least 1".into()));
+        }
+        if self.limits.max_document_bytes == 0 {
+            return Err(ConfigError::Invalid(
+                "limits.max_document_bytes must be at least 1".into(),
+            ));
+        }
+        if self.limits.max_connections == 0 {
+            return Err(ConfigError::Invalid(
+                "limits.max_connections must be at least 1".into(),
+            ));
+        }
+        if self.admin_token.as_deref() == Some("") {
+            return Err(ConfigError::Invalid("admin_token must not be empty".into()));
+        }
+        if self.port != 0 && self.http_port == Some(self.port) {
+            return Err(ConfigError::Invalid(format!(
+                "http_port and port are both {}",
+                self.port
+            )));
+        }
+        if let Some(dir) = &self.data_dir {
+            if !dir.is_dir() {
+                return Err(ConfigError::Invalid(format!(
+                    "data_dir {} is not a directory",
+                    dir.display()
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Builds a [`ServerConfig`], validating it at the end
+#[derive(Debug, Clone)]
+pub struct ServerConfigBuilder {
+    config: ServerConfig,
+}
+impl ServerConfigBuilder {
+    /// Replace the configuration built so far with the contents of the TOML file at `path`.
+    /// Keys missing from the file take their default values, so call this before any setters
+    /// that should override the file.
+    pub fn file<P: AsRef<Path>>(mut self, path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path).map_err(|e| ConfigError::Io {
+            path: path.to_path_buf(),
+            error: e,
+        })?;
+        self.config = toml::from_str(&contents).map_err(|e| ConfigError::Parse {
+            path: path.to_path_buf(),
+            error: e,
+        })?;
+        Ok(self)
+    }
+
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.config.workers = workers;
+        self
+    }
+    pub fn max_workers(mut self, max_workers: usize) -> Self {
+        self.config.max_workers = Some(max_workers);
+        self
+    }
+    pub fn worker_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.worker_idle_timeout_ms = timeout_to_ms(Some(timeout));
+        self
+    }
+    pub fn queue_capacity(mut self, queue_capacity: usize) -> Self {
+        self.config.queue_capacity = queue_capacity;
+        self
+    }
+    pub fn buckets(mut self, buckets: usize) -> Self {
+        self.config.buckets = buckets;
+        self
+    }
+    pub fn bind(mut self, bind: IpAddr) -> Self {
+        self.config.bind = bind;
+        self
+    }
+    pub fn port(mut self, port: u16) -> Self {
+        self.config.port = port;
+        self
+    }
+    pub fn http_port(mut self, http_port: u16) -> Self {
+        self.config.http_port = Some(http_port);
+        self
+    }
+    pub fn unix_socket<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.config.unix_socket = Some(path.into());
+        self
+    }
+    pub fn data_dir<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.config.data_dir = Some(path.into());
+        self
+    }
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.config.shutdown_timeout_ms = timeout_to_ms(Some(timeout));
+        self
+    }
+    pub fn admin_token<S: Into<String>>(mut self, token: S) -> Self {
+        self.config.admin_token = Some(token.into());
+        self
+    }
+    pub fn max_document_bytes(mut self, bytes: u64) -> Self {
+        self.config.limits.max_document_bytes = bytes;
+        self
+    }
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.limits.idle_timeout_ms = timeout_to_ms(timeout);
+        self
+    }
+    pub fn read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.limits.read_timeout_ms = timeout_to_ms(timeout);
+        self
+    }
+    pub fn write_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.limits.write_timeout_ms = timeout_to_ms(timeout);
+        self
+    }
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.config.limits.max_connections = max_connections;
+        self
+    }
+    pub fn slow_query_threshold(mut self, threshold: Duration) -> Self {
+        let threshold_ms = threshold.as_millis().try_into().unwrap_or(u64::MAX);
+        self.config.slow_query_log.threshold_ms = threshold_ms;
+        self
+    }
+    pub fn slow_query_log_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.config.slow_query_log.path = Some(path.into());
+        self
+    }
+    pub fn slow_queries_kept(mut self, keep: usize) -> Self {
+        self.config.slow_query_log.keep = keep;
+        self
+    }
+    pub fn analyzer(mut self, analyzer: Analyzer) -> Self {
+        self.config.analyzer = analyzer;
+        self
+    }
+
+    /// Validate the configuration and return it
+    pub fn build(self) -> Result<ServerConfig, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+// Timeouts shorter than a millisecond round up, so they don't turn into "no timeout"
+fn timeout_to_ms(timeout: Option<Duration>) -> u64 {
+    match timeout {
+        Some(timeout) => timeout.as_millis().clamp(1, u64::MAX as u128) as u64,
+        None => 0,
+    }
+}
+
+/// An error in a server configuration
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The configuration file couldn't be read
+    Io { path: PathBuf, error: std::io::Error },
+    /// The configuration file isn't valid TOML, or has unknown or mistyped keys
+    Parse { path: PathBuf, error: toml::de::Error },
+    /// A setting has a value the server can't run with
+    Invalid(String),
+}
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, error } => {
+                write!(f, "failed to read {}: {}", path.display(), error)
+            }
+            ConfigError::Parse { path, error } => {
+                write!(f, "invalid configuration in {}: {}", path.display(), error)
+            }
+            ConfigError::Invalid(message) => write!(f, "invalid configuration: {}", message),
+        }
+    }
+}
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io { error, .. } => Some(error),
+            ConfigError::Parse { error, .. } => Some(error),
+            ConfigError::Invalid(_) => None,
+        }
+    }
+}
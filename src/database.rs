@@ -1,29 +1,85 @@
+use crate::cancel::{CancellationToken, Cancelled};
+use crate::message::DocId;
 use crate::multimap::ConcurrentMultiMap;
-use std::sync::Mutex;
+use crate::pool::{Priority, ThreadPool};
+use serde::Deserialize;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
 // The archive struct contains two data structures: a ConcurrentMultiMap for storing the
-// reverse index that maps words to the documents they appear in, and a Mutex<Vec<String>> for
+// reverse index that maps words to the documents they appear in, and a Mutex<Vec<Arc<str>>> for
 // storing the documents themselves. Since the documents themselves aren't accessed as often, it's
-// ok to keep them behind a single mutex.
+// ok to keep them behind a single mutex. Documents are reference counted so that a large document
+// can be streamed to a client without holding the lock or copying it.
 
 /// A document database that allows clients to publish documents and
 /// search for documents containing specific words.
 pub struct Database {
     /// A map from words to the set of documents that contain them
-    reverse_index: ConcurrentMultiMap<String, usize>,
+    reverse_index: ConcurrentMultiMap<String, DocId>,
     /// A store of all documents in the database
-    blob_store: Mutex<Vec<String>>,
+    blob_store: Mutex<Vec<Arc<str>>>,
+    /// How documents and queries are split into indexable words
+    analyzer: Analyzer,
+    /// The number of terms indexed across all documents, counting repeats
+    tokens: AtomicU64,
 }
 
-const BUCKETS: usize = 128;
+/// The number of buckets in the reverse index unless configured otherwise
+pub const DEFAULT_BUCKETS: usize = 128;
+
+// Documents are indexed in parallel by `publish_on` in pieces of about this many bytes
+const INDEX_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Options for turning the words of a document (or a search query) into index terms. By default
+/// words are indexed exactly as they appear between whitespace.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Analyzer {
+    /// Index and search words in lowercase, so searches are case-insensitive
+    pub lowercase: bool,
+    /// Remove leading and trailing punctuation, so "whale," and "whale" are the same word
+    pub strip_punctuation: bool,
+}
+impl Analyzer {
+    /// Turn a whitespace-separated word into the term it is indexed under. Returns an empty
+    /// string for words that consist only of characters the analyzer removes.
+    pub fn term(&self, word: &str) -> String {
+        let word = if self.strip_punctuation {
+            word.trim_matches(|c: char| c.is_ascii_punctuation())
+        } else {
+            word
+        };
+        if self.lowercase {
+            word.to_lowercase()
+        } else {
+            word.to_string()
+        }
+    }
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Database {
     // TODO:
     // Create a new empty archive. The map should have `BUCKETS` buckets.
     pub fn new() -> Self {
+        Self::with_options(DEFAULT_BUCKETS, Analyzer::default())
+    }
+
+    /// Create a new empty archive whose reverse index has `buckets` buckets and which indexes
+    /// words with `analyzer`
+    pub fn with_options(buckets: usize, analyzer: Analyzer) -> Self {
         Self {
-            reverse_index: ConcurrentMultiMap::new(BUCKETS),
+            reverse_index: ConcurrentMultiMap::new(buckets),
             blob_store: Mutex::new(Vec::new()),
+            analyzer,
+            tokens: AtomicU64::new(0),
         }
     }
 
@@ -35,30 +91,169 @@ impl Database {
     //    whitespace is sufficient. It is up to you whether to also perform transformations like
     //    converting to lowercase or removing numerals.
     // 3. Add the document to the blob store
-    pub fn publish(&self, doc: String) -> usize {
-        let id = {
-            let mut store = self.blob_store.lock().unwrap();
-            store.push(doc.clone());
-            store.len() - 1
-        };
+    pub fn publish(&self, doc: String) -> DocId {
+        let (id, doc) = self.store(doc);
+        let tokens = self.index(&doc, id);
+        self.tokens.fetch_add(tokens, Ordering::Relaxed);
+        id
+    }
 
-        let words = doc.split_whitespace();
-        for word in words {
-            self.reverse_index.set(word.to_string(), id);
-        }
+    /// Publish a document unless `token` is cancelled first. Once the document is stored it is
+    /// indexed in full, however long that takes, so searches never see half of it.
+    pub fn publish_cancellable(
+        &self,
+        doc: String,
+        token: &CancellationToken,
+    ) -> Result<DocId, Cancelled> {
+        token.check()?;
+        Ok(self.publish(doc))
+    }
 
+    /// Publish a document, indexing large ones in pieces in parallel on `pool`. The pieces
+    /// borrow the stored document rather than copying it, and are queued as bulk jobs so
+    /// requests waiting for a worker start first.
+    ///
+    /// This waits for the pieces to be indexed, so don't call it from a job running on `pool`.
+    pub fn publish_on(&self, doc: String, pool: &ThreadPool) -> DocId {
+        let (id, doc) = self.store(doc);
+        let tokens = AtomicU64::new(0);
+        pool.scope(|scope| {
+            for chunk in chunks(&doc, INDEX_CHUNK_BYTES) {
+                let tokens = &tokens;
+                scope.spawn_with_priority(Priority::Bulk, move || {
+                    tokens.fetch_add(self.index(chunk, id), Ordering::Relaxed);
+                });
+            }
+        });
+        self.tokens.fetch_add(tokens.into_inner(), Ordering::Relaxed);
         id
     }
+
+    // Add a document to the blob store, returning its new ID and a shared handle to it
+    fn store(&self, doc: String) -> (DocId, Arc<str>) {
+        let doc: Arc<str> = doc.into();
+        let mut store = self.blob_store.lock().unwrap();
+        store.push(Arc::clone(&doc));
+        let id = DocId::from_index(store.len() - 1).expect("document index exceeds 64 bits");
+        (id, doc)
+    }
+
+    // Map each word of `text` to `id` in the reverse index, returning how many terms were indexed
+    fn index(&self, text: &str, id: DocId) -> u64 {
+        let mut tokens = 0;
+        for word in text.split_whitespace() {
+            let term = self.analyzer.term(word);
+            if !term.is_empty() {
+                self.reverse_index.set(term, id);
+                tokens += 1;
+            }
+        }
+        tokens
+    }
     // TODO:
     // Use the reverse index to get the set of documents that contain the given word.
-    pub fn search(&self, word: &str) -> Vec<usize> {
-        self.reverse_index.get(word)
+    pub fn search(&self, word: &str) -> Vec<DocId> {
+        self.reverse_index.get(&self.analyzer.term(word))
+    }
+
+    /// Search for the documents containing `word`, giving up if `token` is cancelled while the
+    /// index is being scanned
+    pub fn search_cancellable(
+        &self,
+        word: &str,
+        token: &CancellationToken,
+    ) -> Result<Vec<DocId>, Cancelled> {
+        self.reverse_index.try_get(&self.analyzer.term(word), || token.check())
     }
     // TODO:
     // Retrieve the document with the given id from the blob store.
     // Return None if the given id is invalid.
-    pub fn retrieve(&self, id: usize) -> Option<String> {
+    pub fn retrieve(&self, id: DocId) -> Option<String> {
+        self.retrieve_shared(id).map(|doc| doc.to_string())
+    }
+
+    /// Retrieve up to `len` bytes of the document with the given id, starting at byte `offset`.
+    /// See [`text_range`] for how the range is adjusted to character boundaries.
+    /// Return None if the id or the offset is invalid.
+    pub fn retrieve_range(&self, id: DocId, offset: u64, len: Option<u64>) -> Option<String> {
+        let doc = self.retrieve_shared(id)?;
+        let range = text_range(&doc, offset, len)?;
+        Some(doc[range].to_string())
+    }
+
+    /// The number of documents published
+    pub fn document_count(&self) -> usize {
+        self.blob_store.lock().unwrap().len()
+    }
+
+    /// The number of distinct terms in the index
+    pub fn vocabulary_size(&self) -> usize {
+        self.reverse_index.key_count()
+    }
+
+    /// The number of terms indexed across all documents, counting repeats
+    pub fn token_count(&self) -> u64 {
+        self.tokens.load(Ordering::Relaxed)
+    }
+
+    /// Retrieve a shared handle to the document with the given id without copying it.
+    /// Return None if the given id is invalid, including ids too large to index the blob store on
+    /// this platform.
+    pub fn retrieve_shared(&self, id: DocId) -> Option<Arc<str>> {
+        let index = id.to_index().ok()?;
         let store = self.blob_store.lock().unwrap();
-        store.get(id).cloned()
+        store.get(index).cloned()
+    }
+}
+
+// Split `text` into pieces of at least `size` bytes (except the last), ending each at whitespace so
+// no word is split between two pieces
+fn chunks(text: &str, size: usize) -> impl Iterator<Item = &str> {
+    let mut rest = text;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let mut end = size.min(rest.len());
+        while !rest.is_char_boundary(end) {
+            end += 1;
+        }
+        let end = rest[end..]
+            .find(char::is_whitespace)
+            .map_or(rest.len(), |i| end + i);
+        let (chunk, tail) = rest.split_at(end);
+        rest = tail;
+        Some(chunk)
+    })
+}
+
+/// Compute the byte range of `doc` covering up to `len` bytes from `offset` (or to the end of the
+/// document if `len` is `None`).
+///
+/// The end of the range is moved back to the nearest character boundary so the result is always
+/// valid UTF-8; if that would leave a non-empty request with nothing to return, it is moved
+/// forward past one character instead. A client paging through a document should therefore use
+/// the length of the returned text, not the requested length, to compute the next offset.
+///
+/// Returns None if `offset` is past the end of the document or falls inside a character.
+pub fn text_range(doc: &str, offset: u64, len: Option<u64>) -> Option<Range<usize>> {
+    let start = usize::try_from(offset).ok()?;
+    if !doc.is_char_boundary(start) {
+        return None;
+    }
+    let mut end = match len {
+        Some(len) => usize::try_from(len)
+            .map_or(doc.len(), |len| start.saturating_add(len))
+            .min(doc.len()),
+        None => doc.len(),
+    };
+    while !doc.is_char_boundary(end) {
+        end -= 1;
+    }
+    if end == start && len != Some(0) {
+        if let Some(c) = doc[start..].chars().next() {
+            end = start + c.len_utf8();
+        }
     }
+    Some(start..end)
 }
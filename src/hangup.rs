@@ -0,0 +1,162 @@
+// Requests that can take a while register their connection here while they run, and one thread
+// per server polls all of them, cancelling a request's token when its client hangs up. Nothing
+// runs on the thread pool, so the checks carry on when every worker is busy, and registering a
+// connection only takes a lock.
+//
+// A client has hung up when its connection is closed in both directions or reset. A client that
+// only shuts down its side for writing, as `nc -N` does once it has sent a request, is still
+// waiting for the answer and isn't counted. Over TCP a client that closes its socket normally
+// looks exactly like that until the server writes to it, so only resets are caught early there;
+// over a Unix socket closing is always caught.
+
+use crate::cancel::CancellationToken;
+use crate::transport::Stream;
+use log::error;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+/// How long the watcher waits for a hang-up before picking up newly registered connections
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+pub(crate) struct PeerWatcher {
+    watched: Mutex<Watched>,
+}
+
+#[derive(Default)]
+struct Watched {
+    next_id: u64,
+    connections: Vec<Connection>,
+}
+
+struct Connection {
+    id: u64,
+    #[cfg(unix)]
+    fd: std::os::unix::io::RawFd,
+    token: CancellationToken,
+}
+
+impl PeerWatcher {
+    /// Start the watcher thread. It exits once the watcher is dropped.
+    pub fn start() -> Arc<Self> {
+        let watcher = Arc::new(PeerWatcher {
+            watched: Mutex::default(),
+        });
+        let weak = Arc::downgrade(&watcher);
+        let spawned = thread::Builder::new()
+            .name("ngram-peer-watcher".to_string())
+            .spawn(move || run(weak));
+        if let Err(e) = spawned {
+            error!("Failed to start the peer watcher, so hang-ups won't be noticed: {}", e);
+        }
+        watcher
+    }
+
+    /// Cancel `token` if the client on `stream` hangs up before the returned guard is dropped,
+    /// or already has, as clients that give up while their request is queued do. Drop the guard
+    /// before the stream, so the watcher never polls a closed connection.
+    pub fn watch(&self, stream: &Stream, token: &CancellationToken) -> Watch<'_> {
+        let mut watched = self.watched.lock().unwrap();
+        let id = watched.next_id;
+        watched.next_id += 1;
+        #[cfg(unix)]
+        {
+            let fd = std::os::unix::io::AsRawFd::as_raw_fd(stream);
+            let mut fds = [pollfd(fd)];
+            if poll(&mut fds, 0) > 0 && hung_up(&fds[0]) {
+                token.cancel();
+            } else {
+                watched.connections.push(Connection {
+                    id,
+                    fd,
+                    token: token.clone(),
+                });
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = (stream, token);
+        Watch { watcher: self, id }
+    }
+}
+
+/// Stops watching a connection when dropped
+pub(crate) struct Watch<'a> {
+    watcher: &'a PeerWatcher,
+    id: u64,
+}
+impl Drop for Watch<'_> {
+    fn drop(&mut self) {
+        let mut watched = self.watcher.watched.lock().unwrap();
+        watched.connections.retain(|connection| connection.id != self.id);
+    }
+}
+
+// Poll the registered connections until the watcher is dropped. The list is copied for each
+// poll, and a connection whose client has hung up is cancelled only if it is still registered,
+// since it may have finished, and its descriptor been reused, while the poll was waiting.
+#[cfg(unix)]
+fn run(watcher: Weak<PeerWatcher>) {
+    let mut fds = Vec::new();
+    let mut ids = Vec::new();
+    loop {
+        let Some(strong) = watcher.upgrade() else {
+            return;
+        };
+        fds.clear();
+        ids.clear();
+        for connection in &strong.watched.lock().unwrap().connections {
+            fds.push(pollfd(connection.fd));
+            ids.push(connection.id);
+        }
+        // Don't keep the server's state alive while waiting
+        drop(strong);
+        if fds.is_empty() {
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+
+        if poll(&mut fds, POLL_INTERVAL.as_millis() as libc::c_int) <= 0 {
+            continue;
+        }
+        let Some(strong) = watcher.upgrade() else {
+            return;
+        };
+        let mut watched = strong.watched.lock().unwrap();
+        for (fd, id) in fds.iter().zip(&ids) {
+            if !hung_up(fd) {
+                continue;
+            }
+            // Stop watching it, or every poll would return straight away until the request ends
+            if let Some(i) = watched.connections.iter().position(|c| c.id == *id) {
+                watched.connections.swap_remove(i).token.cancel();
+            }
+        }
+    }
+}
+
+// No events are asked for, since hang-ups and errors are always reported
+#[cfg(unix)]
+fn pollfd(fd: std::os::unix::io::RawFd) -> libc::pollfd {
+    libc::pollfd {
+        fd,
+        events: 0,
+        revents: 0,
+    }
+}
+
+// Wait up to `timeout_ms` for any of `fds` to be hung up, returning how many were, or a negative
+// number if the poll failed
+#[cfg(unix)]
+fn poll(fds: &mut [libc::pollfd], timeout_ms: libc::c_int) -> libc::c_int {
+    // SAFETY: `fds` is a valid array of `fds.len()` pollfd structs for the whole call
+    unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms) }
+}
+
+#[cfg(unix)]
+fn hung_up(fd: &libc::pollfd) -> bool {
+    fd.revents & (libc::POLLHUP | libc::POLLERR) != 0
+}
+
+// Without `poll` hang-ups are only noticed when the response can't be written
+#[cfg(not(unix))]
+fn run(_watcher: Weak<PeerWatcher>) {}
@@ -0,0 +1,191 @@
+// Just enough HTTP/1.1 for the REST gateway in `server`: one request per connection, bodies
+// delimited by Content-Length, and the connection closed after the response. Anything fancier
+// (chunked uploads, keep-alive, TLS) is expected to be handled by a reverse proxy in front of us.
+
+use std::io::{self, BufRead, Read, Write};
+
+/// The largest request head (request line and headers) we are willing to read
+const MAX_HEAD_BYTES: u64 = 16 * 1024;
+
+/// A parsed HTTP request
+#[derive(Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    /// The path without the query string, e.g. `/documents/3`
+    pub path: String,
+    /// The decoded query parameters, in the order they appeared
+    pub query: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+impl HttpRequest {
+    /// The value of the first query parameter called `name`
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Read a request from `reader`, refusing bodies larger than `max_body` bytes
+    pub fn read<R: BufRead>(reader: &mut R, max_body: u64) -> io::Result<Self> {
+        let mut head = reader.take(MAX_HEAD_BYTES);
+
+        let mut line = String::new();
+        head.read_line(&mut line)?;
+        let mut parts = line.split_whitespace();
+        let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => {
+                (method.to_string(), target.to_string())
+            }
+            _ => return Err(invalid("malformed request line")),
+        };
+
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            if head.read_line(&mut header)? == 0 {
+                return Err(invalid("request head too large or truncated"));
+            }
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let (name, value) = header
+                .split_once(':')
+                .ok_or_else(|| invalid("malformed header"))?;
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid("invalid Content-Length"))?;
+            } else if name.trim().eq_ignore_ascii_case("transfer-encoding") {
+                return Err(invalid("Transfer-Encoding is not supported"));
+            }
+        }
+        if content_length > max_body {
+            return Err(invalid("request body too large"));
+        }
+
+        let mut body = Vec::new();
+        reader.take(content_length).read_to_end(&mut body)?;
+        if body.len() as u64 != content_length {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, parse_query(query)),
+            None => (target.as_str(), Vec::new()),
+        };
+        Ok(HttpRequest {
+            method,
+            path: path.to_string(),
+            query,
+            body,
+        })
+    }
+}
+
+/// An HTTP response with a body of type `content_type`
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+impl HttpResponse {
+    pub fn json(status: u16, body: &serde_json::Value) -> Self {
+        let mut body = serde_json::to_vec(body).expect("JSON values always serialize");
+        body.push(b'\n');
+        HttpResponse {
+            status,
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    /// A plain text body, such as Prometheus metrics
+    pub fn text(status: u16, body: String) -> Self {
+        HttpResponse {
+            status,
+            content_type: "text/plain; version=0.0.4",
+            body: body.into_bytes(),
+        }
+    }
+
+    /// A JSON body of the form `{"error": message}`
+    pub fn error(status: u16, message: &str) -> Self {
+        Self::json(status, &serde_json::json!({ "error": message }))
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            reason_phrase(self.status),
+            self.content_type,
+            self.body.len()
+        )?;
+        writer.write_all(&self.body)?;
+        writer.flush()
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect()
+}
+
+// Decode `%XX` escapes and `+` as a space. Invalid escapes are kept as they are, and byte
+// sequences that don't decode to UTF-8 are replaced, since a query word can never match them.
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => match bytes.get(i + 1..i + 3).and_then(hex_byte) {
+                Some(byte) => {
+                    decoded.push(byte);
+                    i += 2;
+                }
+                None => decoded.push(b'%'),
+            },
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn hex_byte(digits: &[u8]) -> Option<u8> {
+    if !digits.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    let digits = std::str::from_utf8(digits).ok()?;
+    u8::from_str_radix(digits, 16).ok()
+}
@@ -1,6 +1,14 @@
+pub mod cancel;
 pub mod client;
+pub mod config;
 pub mod database;
+mod hangup;
+mod http;
+pub mod logging;
 pub mod message;
+pub mod metrics;
 pub mod multimap;
 pub mod pool;
 pub mod server;
+mod slowlog;
+pub mod transport;
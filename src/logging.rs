@@ -0,0 +1,149 @@
+// The library logs through the `log` facade and never installs a logger itself, so embedding it
+// (or running the tests) is silent unless the caller asks for output. This module provides the
+// logger the command line tool installs: one line per record on stderr, as text or as JSON.
+
+use log::kv::{self, Key, Value, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How log lines are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// `2024-05-01T12:00:00.000Z INFO ngram::server: message key=value`
+    #[default]
+    Text,
+    /// One JSON object per line, with the key-value pairs of the record as fields
+    Json,
+}
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {:?}, expected text or json", s)),
+        }
+    }
+}
+
+/// Install a logger that writes records at `level` or above to stderr in `format`. Fails if a
+/// logger has already been installed.
+pub fn init(level: LevelFilter, format: LogFormat) -> Result<(), SetLoggerError> {
+    log::set_boxed_logger(Box::new(Logger { level, format }))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+struct Logger {
+    level: LevelFilter,
+    format: LogFormat,
+}
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = match self.format {
+            LogFormat::Text => text_line(record),
+            LogFormat::Json => json_line(record),
+        };
+        // Write the whole line at once so lines from different threads don't interleave
+        let _ = io::stderr().lock().write_all(line.as_bytes());
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+fn text_line(record: &Record) -> String {
+    let mut line = format!(
+        "{} {:<5} {}: {}",
+        timestamp(),
+        record.level(),
+        record.target(),
+        record.args()
+    );
+    let _ = record.key_values().visit(&mut TextFields(&mut line));
+    line.push('\n');
+    line
+}
+
+struct TextFields<'a>(&'a mut String);
+impl<'kvs> VisitSource<'kvs> for TextFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let _ = write!(self.0, " {}={}", key, value);
+        Ok(())
+    }
+}
+
+fn json_line(record: &Record) -> String {
+    let mut fields = serde_json::Map::new();
+    fields.insert("ts".into(), timestamp().into());
+    fields.insert("level".into(), record.level().as_str().into());
+    fields.insert("target".into(), record.target().into());
+    fields.insert("msg".into(), record.args().to_string().into());
+    let _ = record.key_values().visit(&mut JsonFields(&mut fields));
+    let mut line = serde_json::Value::Object(fields).to_string();
+    line.push('\n');
+    line
+}
+
+struct JsonFields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        // Keep numbers and booleans as JSON numbers and booleans so they can be queried as such
+        let value = if let Some(n) = value.to_u64() {
+            n.into()
+        } else if let Some(n) = value.to_i64() {
+            n.into()
+        } else if let Some(b) = value.to_bool() {
+            b.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+// The current time in UTC as RFC 3339 with milliseconds, e.g. `2024-05-01T12:00:00.000Z`
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = now.as_secs();
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+    // Convert days since the epoch to a civil date (Howard Hinnant's `civil_from_days`)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524
+        - day_of_era / 146_096)
+        / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3_600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        now.subsec_millis()
+    )
+}
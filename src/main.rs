@@ -1,6 +1,16 @@
 use clap::{Parser, Subcommand};
+use log::LevelFilter;
 use ngram::client::Client;
+use ngram::config::ServerConfig;
+use ngram::logging::{self, LogFormat};
+use ngram::message::{DocId, JsonCodec, Response, Stats};
+use ngram::metrics::LATENCY_BUCKETS_MICROS;
 use ngram::server::Server;
+use std::fs::File;
+use std::io::BufWriter;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
 
 // TODO:
 // Fill out the `Args` struct to parse the command line arguments. You may find clap "subcommands"
@@ -9,6 +19,14 @@ use ngram::server::Server;
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// The most detailed log messages to show: off, error, warn, info, debug or trace
+    #[arg(long, global = true, default_value_t = LevelFilter::Info)]
+    log_level: LevelFilter,
+
+    /// Write log lines as text or as JSON objects
+    #[arg(long, global = true, default_value = "text")]
+    log_format: LogFormat,
+
     #[command(subcommand)]
     command: Command,
 }
@@ -17,12 +35,21 @@ struct Args {
 enum Command {
     /// Start the client to send requests to the server
     Client {
-        /// The server address to connect to (e.g., "127.0.0.1")
+        /// The server address to connect to (e.g., "127.0.0.1", or "unix:/path/to/socket")
         server_address: String,
         
-        /// The port number to connect to on the server
+        /// The port number to connect to on the server (ignored for unix: addresses)
         server_port: u16,
 
+        /// Talk to the server in newline-delimited JSON instead of the binary format
+        #[arg(long)]
+        json: bool,
+
+        /// Ask the server to give up on the request if it can't answer within this many
+        /// milliseconds
+        #[arg(long)]
+        timeout_ms: Option<u64>,
+
         /// The client operation: publish, search, or retrieve
         #[command(subcommand)]
         operation: ClientCommand,
@@ -30,8 +57,49 @@ enum Command {
 
     /// Start the server to listen for incoming client requests
     Server {
-        /// The port number on which the server will listen
-        listen_port: u16,
+        /// The port number on which the server will listen (0 picks a free port)
+        listen_port: Option<u16>,
+
+        /// Read settings from this TOML file; other flags override its values
+        #[arg(long)]
+        config: Option<PathBuf>,
+
+        /// The address to listen on, e.g. 0.0.0.0 or :: to accept connections from other hosts
+        #[arg(long)]
+        bind: Option<IpAddr>,
+
+        /// Also serve the HTTP/REST gateway on this port
+        #[arg(long)]
+        http_port: Option<u16>,
+
+        /// Also accept requests on a Unix domain socket at this path
+        #[cfg(unix)]
+        #[arg(long)]
+        unix: Option<PathBuf>,
+
+        /// The number of worker threads handling requests
+        #[arg(long)]
+        workers: Option<usize>,
+
+        /// Add worker threads, up to this many, while connections are waiting for one
+        #[arg(long)]
+        max_workers: Option<usize>,
+
+        /// How many connections may wait for a free worker before new ones are turned away
+        #[arg(long)]
+        queue_capacity: Option<usize>,
+
+        /// The number of buckets in the reverse index
+        #[arg(long)]
+        buckets: Option<usize>,
+
+        /// Publish every file in this directory at startup
+        #[arg(long)]
+        data_dir: Option<PathBuf>,
+
+        /// Require this token for admin requests other than health and readiness checks
+        #[arg(long)]
+        admin_token: Option<String>,
     },
 }
 
@@ -52,23 +120,87 @@ enum ClientCommand {
     /// Retrieve a document from the server by its document ID
     Retrieve {
         /// The ID of the document to retrieve
-        document_id: usize,
+        document_id: u64,
+
+        /// The byte offset to start retrieving from
+        #[arg(long, default_value_t = 0)]
+        offset: u64,
+
+        /// The maximum number of bytes to retrieve (defaults to the rest of the document)
+        #[arg(long, conflicts_with = "output")]
+        length: Option<u64>,
+
+        /// Stream the document into this file instead of printing the response
+        #[arg(long, short)]
+        output: Option<String>,
+
+        /// The size of each streamed chunk in bytes, when writing to a file
+        #[arg(long, default_value_t = 64 * 1024)]
+        chunk_size: u32,
+    },
+
+    /// Show the server's statistics
+    Stats,
+
+    /// Operations for whoever runs the server
+    Admin {
+        /// The token the server was configured with
+        #[arg(long)]
+        token: Option<String>,
+
+        #[command(subcommand)]
+        command: AdminSubcommand,
     },
 }
 
+#[derive(Subcommand, Debug)]
+enum AdminSubcommand {
+    /// List the most recent requests that took longer than the slow query threshold
+    SlowQueries {
+        /// The most requests to list
+        #[arg(long, default_value_t = 20)]
+        limit: u32,
+    },
+
+    /// Check that the server is running
+    Health,
+
+    /// Check that the server has finished loading and accepts requests; exits with 1 if not
+    Ready,
+
+    /// Stop the server, letting requests in progress finish unless --now is given
+    Shutdown {
+        /// Drop queued connections rather than waiting for them
+        #[arg(long)]
+        now: bool,
+    },
+
+    /// Flush the server's log output
+    Flush,
+}
+
 // TODO:
 // Inspect the contents of the `args` struct that has been created from the command line arguments
 // the user passed. Depending on the arguments, either start a server or make a client and send the
 // appropriate request. You may find it helpful to print the request response.
 fn main() {
     let args = Args::parse();
+    logging::init(args.log_level, args.log_format).expect("no logger is installed yet");
     match args.command {
         Command::Client {
             server_address,
             server_port,
+            json,
+            timeout_ms,
             operation,
         } => {
-            let client = Client::new(&server_address, server_port);
+            let mut client = Client::new(&server_address, server_port);
+            if json {
+                client = client.with_codec(&JsonCodec);
+            }
+            if let Some(timeout_ms) = timeout_ms {
+                client = client.with_timeout(Duration::from_millis(timeout_ms));
+            }
             match operation {
                 ClientCommand::Publish { path } => {
                     println!(
@@ -90,22 +222,220 @@ fn main() {
                         None => eprintln!("Failed to search document archive"),
                     }
                 }
-                ClientCommand::Retrieve { document_id } => {
+                ClientCommand::Retrieve {
+                    document_id,
+                    offset,
+                    length,
+                    output,
+                    chunk_size,
+                } => {
+                    let document_id = DocId(document_id);
                     println!(
                         "Connecting to {}:{} to retrieve document with ID: {}",
                         server_address, server_port, document_id
                     );
-                    match client.retrieve(document_id) {
-                        Some(response) => println!("Response: {:?}", response),
-                        None => eprintln!("Failed to retrieve document"),
+                    if let Some(output) = output {
+                        let file = match File::create(&output) {
+                            Ok(file) => file,
+                            Err(e) => {
+                                eprintln!("Failed to create {}: {}", output, e);
+                                return;
+                            }
+                        };
+                        let writer = BufWriter::new(file);
+                        let written =
+                            client.retrieve_to_writer(document_id, offset, chunk_size, writer);
+                        match written {
+                            Some(bytes) => println!("Wrote {} bytes to {}", bytes, output),
+                            None => eprintln!("Failed to retrieve document"),
+                        }
+                    } else {
+                        match client.retrieve_range(document_id, offset, length) {
+                            Some(response) => println!("Response: {:?}", response),
+                            None => eprintln!("Failed to retrieve document"),
+                        }
+                    }
+                }
+                ClientCommand::Stats => match client.stats() {
+                    Some(Response::Stats(stats)) => print_stats(&stats),
+                    Some(response) => println!("Response: {:?}", response),
+                    None => eprintln!("Failed to get server statistics"),
+                },
+                ClientCommand::Admin { token, command } => {
+                    if let Some(token) = token {
+                        client = client.with_admin_token(token);
                     }
+                    run_admin(&client, command);
                 }
             }
         }
-        Command::Server { listen_port } => {
-            println!("Starting server and listening on port: {}", listen_port);
-            let server = Server::new();
-            server.run(listen_port);
+        Command::Server {
+            listen_port,
+            config,
+            bind,
+            http_port,
+            #[cfg(unix)]
+            unix,
+            workers,
+            max_workers,
+            queue_capacity,
+            buckets,
+            data_dir,
+            admin_token,
+        } => {
+            // Settings come from the defaults, then the config file, then the command line
+            let mut builder = ServerConfig::builder();
+            if let Some(path) = config {
+                builder = match builder.file(&path) {
+                    Ok(builder) => builder,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        std::process::exit(1);
+                    }
+                };
+            }
+            if let Some(port) = listen_port {
+                builder = builder.port(port);
+            }
+            if let Some(bind) = bind {
+                builder = builder.bind(bind);
+            }
+            if let Some(http_port) = http_port {
+                builder = builder.http_port(http_port);
+            }
+            #[cfg(unix)]
+            if let Some(path) = unix {
+                builder = builder.unix_socket(path);
+            }
+            if let Some(workers) = workers {
+                builder = builder.workers(workers);
+            }
+            if let Some(max_workers) = max_workers {
+                builder = builder.max_workers(max_workers);
+            }
+            if let Some(queue_capacity) = queue_capacity {
+                builder = builder.queue_capacity(queue_capacity);
+            }
+            if let Some(buckets) = buckets {
+                builder = builder.buckets(buckets);
+            }
+            if let Some(data_dir) = data_dir {
+                builder = builder.data_dir(data_dir);
+            }
+            if let Some(token) = admin_token {
+                builder = builder.admin_token(token);
+            }
+            let config = match builder.build() {
+                Ok(config) => config,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            println!(
+                "Starting server and listening on {}",
+                SocketAddr::new(config.bind, config.port)
+            );
+            let server = Server::with_config(config);
+            if let Err(e) = server.serve() {
+                eprintln!("Server failed: {}", e);
+                std::process::exit(1);
+            }
         }
     }
 }
+
+fn run_admin(client: &Client, command: AdminSubcommand) {
+    match command {
+        AdminSubcommand::SlowQueries { limit } => match client.slow_queries(limit) {
+            Some(Response::SlowQueries(queries)) => {
+                for query in queries {
+                    println!(
+                        "{:>10} us  {:>8} results  {}  {}",
+                        query.duration_micros, query.result_size, query.peer, query.summary
+                    );
+                }
+            }
+            Some(response) => println!("Response: {:?}", response),
+            None => eprintln!("Failed to get slow queries"),
+        },
+        AdminSubcommand::Health => match client.health() {
+            Some(Response::Health(health)) => println!(
+                "Up for {} ms with {} connections in flight",
+                health.uptime_ms, health.in_flight
+            ),
+            Some(response) => println!("Response: {:?}", response),
+            None => {
+                eprintln!("Server is not responding");
+                std::process::exit(1);
+            }
+        },
+        AdminSubcommand::Ready => match client.ready() {
+            Some(Response::Ready(true)) => println!("Ready"),
+            Some(Response::Ready(false)) => {
+                println!("Not ready");
+                std::process::exit(1);
+            }
+            Some(response) => println!("Response: {:?}", response),
+            None => {
+                eprintln!("Server is not responding");
+                std::process::exit(1);
+            }
+        },
+        AdminSubcommand::Shutdown { now } => match client.shutdown(!now) {
+            Some(Response::Ok) => println!("Server is shutting down"),
+            Some(response) => println!("Response: {:?}", response),
+            None => eprintln!("Failed to shut down the server"),
+        },
+        AdminSubcommand::Flush => match client.flush() {
+            Some(Response::Ok) => println!("Flushed"),
+            Some(response) => println!("Response: {:?}", response),
+            None => eprintln!("Failed to flush the server's logs"),
+        },
+    }
+}
+
+fn print_stats(stats: &Stats) {
+    println!("Documents:      {}", stats.documents);
+    println!("Vocabulary:     {}", stats.vocabulary);
+    println!("Total tokens:   {}", stats.total_tokens);
+    println!("Queue depth:    {}", stats.queue_depth);
+    println!("Active workers: {}", stats.active_workers);
+    println!("Shed:           {}", stats.shed);
+    println!("Panics:         {}", stats.panics);
+    println!();
+    println!(
+        "{:<16} {:>10} {:>10} {:>12}  latency histogram (us)",
+        "request", "count", "failures", "mean (us)"
+    );
+    for request in &stats.requests {
+        let mean = request.latency_sum_micros.checked_div(request.count).unwrap_or(0);
+        let histogram = request
+            .latency_buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(i, count)| match LATENCY_BUCKETS_MICROS.get(i) {
+                Some(bound) => format!("<={}:{}", bound, count),
+                None => format!(">{}:{}", LATENCY_BUCKETS_MICROS.last().unwrap(), count),
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        println!(
+            "{:<16} {:>10} {:>10} {:>12}  {}",
+            request.kind, request.count, request.failures, mean, histogram
+        );
+    }
+    println!();
+    println!("{:<16} {:>10} {:>12} {:>10}", "worker", "jobs", "busy (ms)", "panics");
+    for worker in &stats.workers {
+        println!(
+            "{:<16} {:>10} {:>12} {:>10}",
+            format!("ngram-worker-{}", worker.id),
+            worker.jobs,
+            worker.busy_micros / 1000,
+            worker.panics
+        );
+    }
+}
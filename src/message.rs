@@ -1,19 +1,151 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, BufRead, Read};
+use std::time::Duration;
+
+/// The identifier of a document in the archive.
+///
+/// IDs are always 64 bits wide on the wire, whatever the width of `usize` on either end, so
+/// servers and clients on different architectures agree on them. Converting to and from an index
+/// into local storage is checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct DocId(pub u64);
+impl DocId {
+    /// Convert a local storage index into an ID
+    pub fn from_index(index: usize) -> Result<Self, DocIdError> {
+        u64::try_from(index)
+            .map(DocId)
+            .map_err(|_| DocIdError { id: index as u128 })
+    }
+    /// Convert the ID into a local storage index, failing if it doesn't fit in a `usize`
+    pub fn to_index(self) -> Result<usize, DocIdError> {
+        usize::try_from(self.0).map_err(|_| DocIdError { id: self.0 as u128 })
+    }
+}
+impl fmt::Display for DocId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+impl From<u64> for DocId {
+    fn from(id: u64) -> Self {
+        DocId(id)
+    }
+}
+
+/// An error for a document ID that can't be represented on this platform
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DocIdError {
+    id: u128,
+}
+impl fmt::Display for DocIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "document id {} is out of range on this platform", self.id)
+    }
+}
+impl std::error::Error for DocIdError {}
+
 /// A request from the client to the server
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub enum Request {
     /// Add the document `doc` to the archive
     Publish { doc: String },
     /// Search for the word `word` in the archive
     Search { word: String },
-    /// Retrieve the document with the index `id` from the archive
-    Retrieve { id: usize },
+    /// Retrieve up to `len` bytes of the document with the index `id`, starting at byte `offset`.
+    /// A `len` of `None` retrieves everything from `offset` to the end of the document.
+    Retrieve {
+        id: DocId,
+        #[serde(default)]
+        offset: u64,
+        #[serde(default)]
+        len: Option<u64>,
+    },
+    /// Stream the document with the index `id` from byte `offset` onwards as a series of
+    /// `RetrieveChunk` responses of at most `chunk_size` bytes, followed by `RetrieveEnd`
+    RetrieveStream {
+        id: DocId,
+        #[serde(default)]
+        offset: u64,
+        chunk_size: u32,
+    },
+    /// Report what the server has been doing, answered with `Stats`
+    Stats,
+    /// An operation for whoever runs the server rather than for readers of the archive. Except
+    /// for `Health` and `Ready`, `token` must match the server's admin token.
+    Admin {
+        #[serde(default)]
+        token: Option<String>,
+        command: AdminCommand,
+    },
+    /// Handle `request`, but give up if it hasn't been answered within `timeout_ms` milliseconds
+    /// of the server reading it, answering with a `Timeout` error instead where it can. Deadlines
+    /// don't nest.
+    Deadline { timeout_ms: u64, request: Box<Request> },
+}
+
+/// The operations of an `Admin` request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AdminCommand {
+    /// List up to `limit` of the most recent slow requests, newest first, answered with
+    /// `SlowQueries`
+    SlowQueries { limit: u32 },
+    /// Check that the server is up, answered with `Health`
+    Health,
+    /// Check whether the server is ready for traffic, answered with `Ready`
+    Ready,
+    /// Stop the server, answered with `Ok` before it stops. A graceful shutdown waits for
+    /// requests in flight, up to the shutdown timeout; otherwise the server exits straight away.
+    Shutdown { graceful: bool },
+    /// Write out anything the server has buffered, answered with `Ok`
+    Flush,
 }
 impl Request {
+    /// Convenience constructor for retrieving a whole document
+    pub fn retrieve(id: DocId) -> Self {
+        Request::Retrieve {
+            id,
+            offset: 0,
+            len: None,
+        }
+    }
+
+    /// Wrap the request in a `Deadline`, replacing any deadline it already has
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        let (request, _) = self.into_parts();
+        Request::Deadline {
+            timeout_ms: timeout.as_millis().try_into().unwrap_or(u64::MAX),
+            request: Box::new(request),
+        }
+    }
+
+    /// Split a request into the request to handle and how long it may take, if it is wrapped in
+    /// a `Deadline`. JSON can't be kept from nesting deadlines, so the shortest one wins.
+    pub fn into_parts(self) -> (Request, Option<Duration>) {
+        let mut request = self;
+        let mut shortest: Option<Duration> = None;
+        while let Request::Deadline { timeout_ms, request: inner } = request {
+            let timeout = Duration::from_millis(timeout_ms);
+            shortest = Some(shortest.map_or(timeout, |shortest| shortest.min(timeout)));
+            request = *inner;
+        }
+        (request, shortest)
+    }
+
     // TODO:
     // Convert the request `self` into a byte vector. See the assignment handout for suggestions on
     // how to represent the request as a series of bytes.
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = vec![];
+        self.write_fields(&mut bytes);
+        append_checksum(&mut bytes);
+        bytes
+    }
+
+    // Append the request to `bytes` without a checksum, so a `Deadline` can contain one
+    fn write_fields(&self, bytes: &mut Vec<u8>) {
         match self {
             Request::Publish { doc } => {
                 bytes.push(0); // Use 0 as a marker for Publish
@@ -27,68 +159,309 @@ impl Request {
                 bytes.extend((word_bytes.len() as u32).to_be_bytes());
                 bytes.extend(word_bytes);
             }
-            Request::Retrieve { id } => {
+            Request::Retrieve { id, offset, len } => {
                 bytes.push(2); // Use 2 as a marker for Retrieve
-                bytes.extend((*id as u64).to_be_bytes());
+                bytes.extend(id.0.to_be_bytes());
+                bytes.extend(offset.to_be_bytes());
+                match len {
+                    Some(len) => {
+                        bytes.push(1);
+                        bytes.extend(len.to_be_bytes());
+                    }
+                    None => bytes.push(0),
+                }
+            }
+            Request::RetrieveStream {
+                id,
+                offset,
+                chunk_size,
+            } => {
+                bytes.push(3); // Use 3 as a marker for RetrieveStream
+                bytes.extend(id.0.to_be_bytes());
+                bytes.extend(offset.to_be_bytes());
+                bytes.extend(chunk_size.to_be_bytes());
+            }
+            Request::Stats => {
+                bytes.push(4); // Use 4 as a marker for Stats
+            }
+            Request::Admin { token, command } => {
+                bytes.push(5); // Use 5 as a marker for Admin
+                match token {
+                    Some(token) => {
+                        bytes.push(1);
+                        bytes.extend((token.len() as u32).to_be_bytes());
+                        bytes.extend(token.as_bytes());
+                    }
+                    None => bytes.push(0),
+                }
+                match command {
+                    AdminCommand::SlowQueries { limit } => {
+                        bytes.push(0);
+                        bytes.extend(limit.to_be_bytes());
+                    }
+                    AdminCommand::Health => bytes.push(1),
+                    AdminCommand::Ready => bytes.push(2),
+                    AdminCommand::Shutdown { graceful } => {
+                        bytes.push(3);
+                        bytes.push(*graceful as u8);
+                    }
+                    AdminCommand::Flush => bytes.push(4),
+                }
+            }
+            Request::Deadline {
+                timeout_ms,
+                request,
+            } => {
+                bytes.push(6); // Use 6 as a marker for Deadline
+                bytes.extend(timeout_ms.to_be_bytes());
+                request.write_fields(bytes);
             }
         }
-        bytes
     }
     // TODO:
     // Read a request from `reader` and return it. Calling `to_bytes` from above and then calling
-    // `from_bytes` should return the original request. If the request is invalid, return `None`.
-    pub fn from_bytes<R: std::io::Read>(mut reader: R) -> Option<Self> {
+    // `from_bytes` should return the original request. If the request is invalid, return an error
+    // describing what was wrong with it.
+    pub fn from_bytes<R: std::io::Read>(reader: R) -> Result<Self, DecodeError> {
+        let mut reader = ChecksumReader::new(reader);
+        let request = Self::read_fields(&mut reader, false)?;
+        reader.verify()?;
+        Ok(request)
+    }
+
+    // Read the fields written by `write_fields`. A request `inside_deadline` can't be another
+    // deadline, which also keeps a hostile message from recursing without bound.
+    fn read_fields<R: Read>(
+        mut reader: &mut R,
+        inside_deadline: bool,
+    ) -> Result<Self, DecodeError> {
         let mut request_type = [0; 1];
-        reader.read_exact(&mut request_type).ok()?;
+        reader.read_exact(&mut request_type)?;
 
-        match request_type[0] {
+        let request = match request_type[0] {
             0 => {
                 // Publish
-                let mut length_bytes = [0; 4];
-                reader.read_exact(&mut length_bytes).ok()?;
-                let length = u32::from_be_bytes(length_bytes) as usize;
-
-                let mut doc_bytes = vec![0; length];
-                reader.read_exact(&mut doc_bytes).ok()?;
-                let doc = String::from_utf8(doc_bytes).ok()?;
-                Some(Request::Publish { doc })
+                let length = read_u32(&mut reader)? as u64;
+                let doc = read_string(&mut reader, length)?;
+                Request::Publish { doc }
             }
             1 => {
                 // Search
-                let mut length_bytes = [0; 4];
-                reader.read_exact(&mut length_bytes).ok()?;
-                let length = u32::from_be_bytes(length_bytes) as usize;
-
-                let mut word_bytes = vec![0; length];
-                reader.read_exact(&mut word_bytes).ok()?;
-                let word = String::from_utf8(word_bytes).ok()?;
-                Some(Request::Search { word })
+                let length = read_u32(&mut reader)? as u64;
+                let word = read_string(&mut reader, length)?;
+                Request::Search { word }
             }
             2 => {
                 // Retrieve
-                let mut id_bytes = [0; 8];
-                reader.read_exact(&mut id_bytes).ok()?;
-                let id = usize::from_be_bytes(id_bytes);
-                Some(Request::Retrieve { id })
+                let id = DocId(read_u64(&mut reader)?);
+                let offset = read_u64(&mut reader)?;
+                let mut has_len = [0; 1];
+                reader.read_exact(&mut has_len)?;
+                let len = match has_len[0] {
+                    0 => None,
+                    1 => Some(read_u64(&mut reader)?),
+                    _ => return Err(DecodeError::Malformed("invalid length flag")),
+                };
+                Request::Retrieve { id, offset, len }
             }
-            _ => None,
-        }
+            3 => {
+                // RetrieveStream
+                let id = DocId(read_u64(&mut reader)?);
+                let offset = read_u64(&mut reader)?;
+                let chunk_size = read_u32(&mut reader)?;
+                Request::RetrieveStream {
+                    id,
+                    offset,
+                    chunk_size,
+                }
+            }
+            4 => {
+                // Stats
+                Request::Stats
+            }
+            5 => {
+                // Admin
+                let token = match read_u8(&mut reader)? {
+                    0 => None,
+                    1 => {
+                        let length = read_u32(&mut reader)? as u64;
+                        Some(read_string(&mut reader, length)?)
+                    }
+                    _ => return Err(DecodeError::Malformed("invalid token flag")),
+                };
+                let command = match read_u8(&mut reader)? {
+                    0 => AdminCommand::SlowQueries {
+                        limit: read_u32(&mut reader)?,
+                    },
+                    1 => AdminCommand::Health,
+                    2 => AdminCommand::Ready,
+                    3 => AdminCommand::Shutdown {
+                        graceful: match read_u8(&mut reader)? {
+                            0 => false,
+                            1 => true,
+                            _ => return Err(DecodeError::Malformed("invalid graceful flag")),
+                        },
+                    },
+                    4 => AdminCommand::Flush,
+                    _ => return Err(DecodeError::Malformed("unknown admin command")),
+                };
+                Request::Admin { token, command }
+            }
+            6 if inside_deadline => return Err(DecodeError::Malformed("nested deadline")),
+            6 => {
+                // Deadline
+                let timeout_ms = read_u64(&mut reader)?;
+                let request = Box::new(Self::read_fields(&mut *reader, true)?);
+                Request::Deadline {
+                    timeout_ms,
+                    request,
+                }
+            }
+            tag => return Err(DecodeError::UnknownTag(tag)),
+        };
+        Ok(request)
     }
 }
 
 /// A response from the server to the client
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub enum Response {
     /// The document was successfully added to the archive with the given index
-    PublishSuccess(usize),
+    PublishSuccess(DocId),
     /// The search for the word was successful, and the indices of the documents containing the
     /// word are returned
-    SearchSuccess(Vec<usize>),
-    /// The retrieval of the document was successful, and the document is returned
+    SearchSuccess(Vec<DocId>),
+    /// The retrieval of the document (or the requested range of it) was successful, and the text
+    /// is returned
     RetrieveSuccess(String),
+    /// The next piece of a streamed document
+    RetrieveChunk(String),
+    /// The streamed document has been sent completely
+    RetrieveEnd,
     /// The request failed
     Failure,
+    /// The server couldn't handle the request for the given reason
+    Error(ErrorCode),
+    /// A snapshot of the server's statistics
+    Stats(Stats),
+    /// The most recent slow requests, newest first
+    SlowQueries(Vec<SlowQuery>),
+    /// The admin request was carried out
+    Ok,
+    /// The server is up
+    Health(Health),
+    /// Whether the server is ready for traffic: its data directory has been loaded and it isn't
+    /// shutting down
+    Ready(bool),
+}
+
+/// The answer to a `Health` admin request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Health {
+    /// How long the server has been running, in milliseconds
+    pub uptime_ms: u64,
+    /// The number of connections being handled or waiting for a worker
+    pub in_flight: u64,
+}
+
+/// A request that took longer than the server's slow query threshold
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SlowQuery {
+    /// When the request finished, in milliseconds since the Unix epoch
+    pub finished_at_ms: u64,
+    /// Who sent the request
+    pub peer: String,
+    /// The request type, such as `search`
+    pub kind: String,
+    /// A short description of the request, such as `search "whale"`
+    pub summary: String,
+    /// The number of IDs or bytes of text in the response
+    pub result_size: u64,
+    /// How long the request took, in microseconds
+    pub duration_micros: u64,
+}
+
+/// A snapshot of what a server has been doing since it started
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stats {
+    /// The number of documents published
+    pub documents: u64,
+    /// The number of distinct terms in the index
+    pub vocabulary: u64,
+    /// The number of terms indexed across all documents, counting repeats
+    pub total_tokens: u64,
+    /// The number of connections waiting for a worker
+    pub queue_depth: u64,
+    /// The number of workers currently handling a connection
+    pub active_workers: u64,
+    /// The number of connections turned away because the queue was full
+    pub shed: u64,
+    /// The number of jobs that panicked on a worker
+    pub panics: u64,
+    /// Counters and latencies for each type of request
+    pub requests: Vec<RequestStats>,
+    /// Counters for each worker thread
+    pub workers: Vec<WorkerStats>,
+}
+
+/// Counters for one type of request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestStats {
+    /// The request type, such as `search`
+    pub kind: String,
+    /// The number of requests handled
+    pub count: u64,
+    /// How many of them failed
+    pub failures: u64,
+    /// The total time spent handling them, in microseconds
+    pub latency_sum_micros: u64,
+    /// How many took at most each of `metrics::LATENCY_BUCKETS_MICROS`, and then how many took
+    /// longer than all of them. The counts are not cumulative.
+    pub latency_buckets: Vec<u64>,
+}
+
+/// Counters for one worker thread
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorkerStats {
+    /// The worker's ID, as in its thread name `ngram-worker-<id>`
+    pub id: u64,
+    /// The number of jobs it has run
+    pub jobs: u64,
+    /// The total time it has spent running jobs, in microseconds
+    pub busy_micros: u64,
+    /// How many of its jobs panicked
+    pub panics: u64,
+}
+/// Why the server couldn't handle a request, as opposed to a request that was handled and failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// The server is too busy to take the request; try again later
+    Overloaded = 0,
+    /// The admin request didn't carry the server's admin token
+    Unauthorized = 1,
+    /// The request's deadline passed before it could be answered
+    Timeout = 2,
+}
+impl ErrorCode {
+    fn from_u8(code: u8) -> Result<Self, DecodeError> {
+        match code {
+            0 => Ok(ErrorCode::Overloaded),
+            1 => Ok(ErrorCode::Unauthorized),
+            2 => Ok(ErrorCode::Timeout),
+            _ => Err(DecodeError::Malformed("unknown error code")),
+        }
+    }
+}
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorCode::Overloaded => f.write_str("server overloaded, retry later"),
+            ErrorCode::Unauthorized => f.write_str("missing or wrong admin token"),
+            ErrorCode::Timeout => f.write_str("deadline passed before the request was answered"),
+        }
+    }
 }
+
 impl Response {
     // TODO:
     // Convert the request `self` into a byte vector. See the assignment handout for suggestions on
@@ -99,74 +472,510 @@ impl Response {
         match self {
             Response::PublishSuccess(id) => {
                 bytes.push(0); // Use 0 as a marker for PublishSuccess
-                bytes.extend((*id as u64).to_be_bytes());
+                bytes.extend(id.0.to_be_bytes());
             }
             Response::SearchSuccess(ids) => {
                 bytes.push(1); // Use 1 as a marker for SearchSuccess
                 bytes.extend((ids.len() as u32).to_be_bytes());
                 for id in ids {
-                    bytes.extend((*id as u64).to_be_bytes());
+                    bytes.extend(id.0.to_be_bytes());
                 }
             }
             Response::RetrieveSuccess(doc) => {
                 bytes.push(2); // Use 2 as a marker for RetrieveSuccess
                 let doc_bytes = doc.as_bytes();
-                bytes.extend((doc_bytes.len() as u32).to_be_bytes());
+                bytes.extend((doc_bytes.len() as u64).to_be_bytes());
                 bytes.extend(doc_bytes);
             }
             Response::Failure => {
                 bytes.push(3); // Use 3 as a marker for Failure
             }
+            Response::RetrieveChunk(chunk) => {
+                bytes.push(4); // Use 4 as a marker for RetrieveChunk
+                let chunk_bytes = chunk.as_bytes();
+                bytes.extend((chunk_bytes.len() as u32).to_be_bytes());
+                bytes.extend(chunk_bytes);
+            }
+            Response::RetrieveEnd => {
+                bytes.push(5); // Use 5 as a marker for RetrieveEnd
+            }
+            Response::Error(code) => {
+                bytes.push(6); // Use 6 as a marker for Error
+                bytes.push(*code as u8);
+            }
+            Response::Stats(stats) => {
+                bytes.push(7); // Use 7 as a marker for Stats
+                for value in [
+                    stats.documents,
+                    stats.vocabulary,
+                    stats.total_tokens,
+                    stats.queue_depth,
+                    stats.active_workers,
+                    stats.shed,
+                    stats.panics,
+                ] {
+                    bytes.extend(value.to_be_bytes());
+                }
+                bytes.extend((stats.requests.len() as u32).to_be_bytes());
+                for request in &stats.requests {
+                    bytes.extend((request.kind.len() as u32).to_be_bytes());
+                    bytes.extend(request.kind.as_bytes());
+                    bytes.extend(request.count.to_be_bytes());
+                    bytes.extend(request.failures.to_be_bytes());
+                    bytes.extend(request.latency_sum_micros.to_be_bytes());
+                    bytes.extend((request.latency_buckets.len() as u32).to_be_bytes());
+                    for count in &request.latency_buckets {
+                        bytes.extend(count.to_be_bytes());
+                    }
+                }
+                bytes.extend((stats.workers.len() as u32).to_be_bytes());
+                for worker in &stats.workers {
+                    for value in [worker.id, worker.jobs, worker.busy_micros, worker.panics] {
+                        bytes.extend(value.to_be_bytes());
+                    }
+                }
+            }
+            Response::SlowQueries(queries) => {
+                bytes.push(8); // Use 8 as a marker for SlowQueries
+                bytes.extend((queries.len() as u32).to_be_bytes());
+                for query in queries {
+                    bytes.extend(query.finished_at_ms.to_be_bytes());
+                    for text in [&query.peer, &query.kind, &query.summary] {
+                        bytes.extend((text.len() as u32).to_be_bytes());
+                        bytes.extend(text.as_bytes());
+                    }
+                    bytes.extend(query.result_size.to_be_bytes());
+                    bytes.extend(query.duration_micros.to_be_bytes());
+                }
+            }
+            Response::Ok => {
+                bytes.push(9); // Use 9 as a marker for Ok
+            }
+            Response::Health(health) => {
+                bytes.push(10); // Use 10 as a marker for Health
+                bytes.extend(health.uptime_ms.to_be_bytes());
+                bytes.extend(health.in_flight.to_be_bytes());
+            }
+            Response::Ready(ready) => {
+                bytes.push(11); // Use 11 as a marker for Ready
+                bytes.push(*ready as u8);
+            }
         }
 
+        append_checksum(&mut bytes);
         bytes
     }
     // TODO:
     // Read a request from `reader` and return it. Calling `to_bytes` from above and then calling
-    // `from_bytes` should return the original request. If the request is invalid, return `None`.
-    pub fn from_bytes<R: std::io::Read>(mut reader: R) -> Option<Self> {
+    // `from_bytes` should return the original request. If the request is invalid, return an error
+    // describing what was wrong with it.
+    pub fn from_bytes<R: std::io::Read>(reader: R) -> Result<Self, DecodeError> {
+        let mut reader = ChecksumReader::new(reader);
         let mut response_type = [0; 1];
-        reader.read_exact(&mut response_type).ok()?;
+        reader.read_exact(&mut response_type)?;
 
-        match response_type[0] {
+        let response = match response_type[0] {
             0 => {
                 // PublishSuccess
-                let mut id_bytes = [0; 8];
-                reader.read_exact(&mut id_bytes).ok()?;
-                let id = usize::from_be_bytes(id_bytes);
-                Some(Response::PublishSuccess(id))
+                let id = DocId(read_u64(&mut reader)?);
+                Response::PublishSuccess(id)
             }
             1 => {
                 // SearchSuccess
-                let mut length_bytes = [0; 4];
-                reader.read_exact(&mut length_bytes).ok()?;
-                let length = u32::from_be_bytes(length_bytes) as usize;
+                let length = read_u32(&mut reader)? as usize;
 
-                let mut ids = Vec::with_capacity(length);
+                let mut ids = Vec::with_capacity(length.min(1024));
                 for _ in 0..length {
-                    let mut id_bytes = [0; 8];
-                    reader.read_exact(&mut id_bytes).ok()?;
-                    ids.push(usize::from_be_bytes(id_bytes));
+                    ids.push(DocId(read_u64(&mut reader)?));
                 }
-                Some(Response::SearchSuccess(ids))
+                Response::SearchSuccess(ids)
             }
             2 => {
                 // RetrieveSuccess
-                let mut length_bytes = [0; 4];
-                reader.read_exact(&mut length_bytes).ok()?;
-                let length = u32::from_be_bytes(length_bytes) as usize;
-
-                let mut doc_bytes = vec![0; length];
-                reader.read_exact(&mut doc_bytes).ok()?;
-                let doc = String::from_utf8(doc_bytes).ok()?;
-                Some(Response::RetrieveSuccess(doc))
+                let length = read_u64(&mut reader)?;
+                let doc = read_string(&mut reader, length)?;
+                Response::RetrieveSuccess(doc)
             }
             3 => {
                 // Failure
-                Some(Response::Failure)
+                Response::Failure
+            }
+            4 => {
+                // RetrieveChunk
+                let length = read_u32(&mut reader)? as u64;
+                let chunk = read_string(&mut reader, length)?;
+                Response::RetrieveChunk(chunk)
+            }
+            5 => {
+                // RetrieveEnd
+                Response::RetrieveEnd
+            }
+            6 => {
+                // Error
+                let mut code = [0; 1];
+                reader.read_exact(&mut code)?;
+                Response::Error(ErrorCode::from_u8(code[0])?)
+            }
+            7 => {
+                // Stats
+                Response::Stats(read_stats(&mut reader)?)
             }
+            8 => {
+                // SlowQueries
+                let length = read_u32(&mut reader)? as usize;
+                let mut queries = Vec::with_capacity(length.min(1024));
+                for _ in 0..length {
+                    queries.push(read_slow_query(&mut reader)?);
+                }
+                Response::SlowQueries(queries)
+            }
+            9 => {
+                // Ok
+                Response::Ok
+            }
+            10 => {
+                // Health
+                Response::Health(Health {
+                    uptime_ms: read_u64(&mut reader)?,
+                    in_flight: read_u64(&mut reader)?,
+                })
+            }
+            11 => {
+                // Ready
+                match read_u8(&mut reader)? {
+                    0 => Response::Ready(false),
+                    1 => Response::Ready(true),
+                    _ => return Err(DecodeError::Malformed("invalid ready flag")),
+                }
+            }
+            tag => return Err(DecodeError::UnknownTag(tag)),
+        };
+        reader.verify()?;
+        Ok(response)
+    }
+}
+
+fn read_stats<R: Read>(reader: &mut R) -> Result<Stats, DecodeError> {
+    let mut stats = Stats {
+        documents: read_u64(reader)?,
+        vocabulary: read_u64(reader)?,
+        total_tokens: read_u64(reader)?,
+        queue_depth: read_u64(reader)?,
+        active_workers: read_u64(reader)?,
+        shed: read_u64(reader)?,
+        panics: read_u64(reader)?,
+        requests: Vec::new(),
+        workers: Vec::new(),
+    };
+    let kinds = read_u32(reader)?;
+    for _ in 0..kinds {
+        let length = read_u32(reader)? as u64;
+        let kind = read_string(reader, length)?;
+        let count = read_u64(reader)?;
+        let failures = read_u64(reader)?;
+        let latency_sum_micros = read_u64(reader)?;
+        let buckets = read_u32(reader)? as usize;
+        let mut latency_buckets = Vec::with_capacity(buckets.min(64));
+        for _ in 0..buckets {
+            latency_buckets.push(read_u64(reader)?);
+        }
+        stats.requests.push(RequestStats {
+            kind,
+            count,
+            failures,
+            latency_sum_micros,
+            latency_buckets,
+        });
+    }
+    let workers = read_u32(reader)?;
+    for _ in 0..workers {
+        stats.workers.push(WorkerStats {
+            id: read_u64(reader)?,
+            jobs: read_u64(reader)?,
+            busy_micros: read_u64(reader)?,
+            panics: read_u64(reader)?,
+        });
+    }
+    Ok(stats)
+}
+
+fn read_slow_query<R: Read>(reader: &mut R) -> Result<SlowQuery, DecodeError> {
+    let finished_at_ms = read_u64(reader)?;
+    let length = read_u32(reader)? as u64;
+    let peer = read_string(reader, length)?;
+    let length = read_u32(reader)? as u64;
+    let kind = read_string(reader, length)?;
+    let length = read_u32(reader)? as u64;
+    let summary = read_string(reader, length)?;
+    Ok(SlowQuery {
+        finished_at_ms,
+        peer,
+        kind,
+        summary,
+        result_size: read_u64(reader)?,
+        duration_micros: read_u64(reader)?,
+    })
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> Result<u8, DecodeError> {
+    let mut byte = [0; 1];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, DecodeError> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64, DecodeError> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+// Read exactly `length` bytes of UTF-8 text. The buffer grows as data arrives rather than being
+// allocated up front, so a corrupt or hostile length prefix can't make us allocate gigabytes.
+fn read_string<R: Read>(reader: &mut R, length: u64) -> Result<String, DecodeError> {
+    let mut bytes = Vec::new();
+    reader.take(length).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != length {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    String::from_utf8(bytes).map_err(|_| DecodeError::InvalidUtf8)
+}
+
+// Every binary message is followed by a CRC-32 (the IEEE polynomial, as used by zlib and
+// Ethernet) of its bytes, in big-endian order, so a frame that was truncated or corrupted on its
+// way to us is rejected rather than decoded into a wrong but plausible message.
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// An incremental CRC-32 computation
+#[derive(Clone, Copy)]
+struct Crc32(u32);
+impl Crc32 {
+    fn new() -> Self {
+        Crc32(0xFFFF_FFFF)
+    }
+    fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = CRC32_TABLE[((self.0 ^ byte as u32) & 0xFF) as usize] ^ (self.0 >> 8);
+        }
+    }
+    fn finish(self) -> u32 {
+        !self.0
+    }
+}
+
+/// The CRC-32 checksum of `bytes`
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(bytes);
+    crc.finish()
+}
+
+fn append_checksum(bytes: &mut Vec<u8>) {
+    let checksum = crc32(bytes);
+    bytes.extend(checksum.to_be_bytes());
+}
+
+// A reader that checksums everything read through it, so a message can be verified as it is
+// decoded without buffering the whole frame first
+struct ChecksumReader<R> {
+    inner: R,
+    crc: Crc32,
+}
+impl<R: Read> ChecksumReader<R> {
+    fn new(inner: R) -> Self {
+        ChecksumReader {
+            inner,
+            crc: Crc32::new(),
+        }
+    }
+
+    // Read the checksum that follows the message and compare it with what we computed
+    fn verify(mut self) -> Result<(), DecodeError> {
+        let actual = self.crc.finish();
+        let mut expected = [0; 4];
+        self.inner.read_exact(&mut expected)?;
+        let expected = u32::from_be_bytes(expected);
+        if expected != actual {
+            return Err(DecodeError::ChecksumMismatch { expected, actual });
+        }
+        Ok(())
+    }
+}
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.crc.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// An error encountered while decoding a message
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The underlying reader failed, or ran out of data in the middle of a message
+    Io(io::Error),
+    /// The message started with a type marker we don't know
+    UnknownTag(u8),
+    /// A string field wasn't valid UTF-8
+    InvalidUtf8,
+    /// Some other field had an impossible value
+    Malformed(&'static str),
+    /// A text-encoded message wasn't valid JSON for any message
+    Json(serde_json::Error),
+    /// The message was read completely but doesn't match the checksum sent with it, so it was
+    /// corrupted on the way
+    ChecksumMismatch { expected: u32, actual: u32 },
+}
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Io(e) => write!(f, "failed to read message: {}", e),
+            DecodeError::UnknownTag(tag) => write!(f, "unknown message type {}", tag),
+            DecodeError::InvalidUtf8 => write!(f, "message contains invalid UTF-8"),
+            DecodeError::Malformed(what) => write!(f, "malformed message: {}", what),
+            DecodeError::Json(e) => write!(f, "invalid JSON message: {}", e),
+            DecodeError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "message checksum mismatch: expected {:08x}, got {:08x}",
+                expected, actual
+            ),
+        }
+    }
+}
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DecodeError::Io(e) => Some(e),
+            DecodeError::Json(e) => Some(e),
             _ => None,
         }
     }
 }
+impl From<io::Error> for DecodeError {
+    fn from(e: io::Error) -> Self {
+        DecodeError::Io(e)
+    }
+}
+
+// Room for everything in a request besides its document, such as the type, lengths, deadline and
+// checksum, or field names in JSON
+const MAX_FRAMING_BYTES: u64 = 1024;
+
+/// A wire format for requests and responses.
+///
+/// Every connection uses a single codec for both directions. The server picks one per connection
+/// by looking at the first byte the client sends (see [`detect_codec`]), so clients choose a codec
+/// simply by using it.
+pub trait Codec: Send + Sync {
+    /// A short name for the codec, for logging
+    fn name(&self) -> &'static str;
+    fn encode_request(&self, request: &Request) -> Vec<u8>;
+    fn decode_request(&self, reader: &mut dyn BufRead) -> Result<Request, DecodeError>;
+    /// The most bytes a request may take up if the documents it carries are at most
+    /// `max_document_bytes` long, so a server can stop reading an oversized one early
+    fn max_request_bytes(&self, max_document_bytes: u64) -> u64;
+    fn encode_response(&self, response: &Response) -> Vec<u8>;
+    fn decode_response(&self, reader: &mut dyn BufRead) -> Result<Response, DecodeError>;
+}
+
+/// The compact, checksummed binary format implemented by `to_bytes` and `from_bytes`
+pub struct BinaryCodec;
+impl Codec for BinaryCodec {
+    fn name(&self) -> &'static str {
+        "binary"
+    }
+    fn encode_request(&self, request: &Request) -> Vec<u8> {
+        request.to_bytes()
+    }
+    fn decode_request(&self, reader: &mut dyn BufRead) -> Result<Request, DecodeError> {
+        Request::from_bytes(reader)
+    }
+    fn max_request_bytes(&self, max_document_bytes: u64) -> u64 {
+        max_document_bytes.saturating_add(MAX_FRAMING_BYTES)
+    }
+    fn encode_response(&self, response: &Response) -> Vec<u8> {
+        response.to_bytes()
+    }
+    fn decode_response(&self, reader: &mut dyn BufRead) -> Result<Response, DecodeError> {
+        Response::from_bytes(reader)
+    }
+}
+
+/// Newline-delimited JSON, one message per line, for scripts and manual debugging, e.g.
+///
+/// ```text
+/// $ echo '{"Search":{"word":"whale"}}' | nc localhost 7878
+/// {"SearchSuccess":[12]}
+/// ```
+pub struct JsonCodec;
+impl JsonCodec {
+    fn encode<T: Serialize>(message: &T) -> Vec<u8> {
+        let mut bytes = serde_json::to_vec(message).expect("messages always serialize to JSON");
+        bytes.push(b'\n');
+        bytes
+    }
+    fn decode<T: DeserializeOwned>(reader: &mut dyn BufRead) -> Result<T, DecodeError> {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        serde_json::from_str(&line).map_err(DecodeError::Json)
+    }
+}
+impl Codec for JsonCodec {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+    fn encode_request(&self, request: &Request) -> Vec<u8> {
+        Self::encode(request)
+    }
+    fn decode_request(&self, reader: &mut dyn BufRead) -> Result<Request, DecodeError> {
+        Self::decode(reader)
+    }
+    fn max_request_bytes(&self, max_document_bytes: u64) -> u64 {
+        // A control character is escaped as `\u00XX`
+        max_document_bytes
+            .saturating_mul(6)
+            .saturating_add(MAX_FRAMING_BYTES)
+    }
+    fn encode_response(&self, response: &Response) -> Vec<u8> {
+        Self::encode(response)
+    }
+    fn decode_response(&self, reader: &mut dyn BufRead) -> Result<Response, DecodeError> {
+        Self::decode(reader)
+    }
+}
 
+/// Pick the codec a client is speaking from the first byte of its first message. JSON messages
+/// always start with `{`, or `"` for requests without fields, neither of which is a valid binary
+/// message type.
+pub fn detect_codec(first_byte: u8) -> &'static dyn Codec {
+    match first_byte {
+        b'{' | b'"' => &JsonCodec,
+        _ => &BinaryCodec,
+    }
+}
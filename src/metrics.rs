@@ -0,0 +1,212 @@
+// Counters and latency histograms for the requests a server handles. They are plain atomics so
+// recording a request never contends with other workers; a `Stats` snapshot reads each counter
+// separately, so it may be very slightly inconsistent while requests are in flight.
+
+use crate::message::{Request, RequestStats, Stats};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// The upper bounds of the latency histogram buckets, in microseconds
+pub const LATENCY_BUCKETS_MICROS: [u64; 10] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 50_000, 250_000, 1_000_000,
+];
+
+/// The types of request that are counted separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+    Publish,
+    Search,
+    Retrieve,
+    RetrieveStream,
+    Stats,
+    Admin,
+}
+impl RequestKind {
+    /// Every kind, in the order they are reported
+    pub const ALL: [RequestKind; 6] = [
+        RequestKind::Publish,
+        RequestKind::Search,
+        RequestKind::Retrieve,
+        RequestKind::RetrieveStream,
+        RequestKind::Stats,
+        RequestKind::Admin,
+    ];
+
+    pub fn of(request: &Request) -> Self {
+        match request {
+            Request::Publish { .. } => RequestKind::Publish,
+            Request::Search { .. } => RequestKind::Search,
+            Request::Retrieve { .. } => RequestKind::Retrieve,
+            Request::RetrieveStream { .. } => RequestKind::RetrieveStream,
+            Request::Stats => RequestKind::Stats,
+            Request::Admin { .. } => RequestKind::Admin,
+            Request::Deadline { request, .. } => Self::of(request),
+        }
+    }
+
+    /// The name the kind is reported under
+    pub fn name(self) -> &'static str {
+        match self {
+            RequestKind::Publish => "publish",
+            RequestKind::Search => "search",
+            RequestKind::Retrieve => "retrieve",
+            RequestKind::RetrieveStream => "retrieve_stream",
+            RequestKind::Stats => "stats",
+            RequestKind::Admin => "admin",
+        }
+    }
+}
+
+#[derive(Default)]
+struct RequestMetrics {
+    count: AtomicU64,
+    failures: AtomicU64,
+    latency_sum_micros: AtomicU64,
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS_MICROS.len() + 1],
+}
+
+/// Request counters for a server
+#[derive(Default)]
+pub struct Metrics {
+    requests: [RequestMetrics; RequestKind::ALL.len()],
+}
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a handled request that took `elapsed`
+    pub fn record(&self, kind: RequestKind, elapsed: Duration, succeeded: bool) {
+        let metrics = &self.requests[kind as usize];
+        let micros = elapsed.as_micros().try_into().unwrap_or(u64::MAX);
+        let bucket = LATENCY_BUCKETS_MICROS
+            .iter()
+            .position(|&bound| micros <= bound)
+            .unwrap_or(LATENCY_BUCKETS_MICROS.len());
+
+        metrics.count.fetch_add(1, Ordering::Relaxed);
+        if !succeeded {
+            metrics.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        metrics.latency_sum_micros.fetch_add(micros, Ordering::Relaxed);
+        metrics.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The counters for every request type
+    pub fn snapshot(&self) -> Vec<RequestStats> {
+        RequestKind::ALL
+            .iter()
+            .zip(&self.requests)
+            .map(|(kind, metrics)| RequestStats {
+                kind: kind.name().to_string(),
+                count: metrics.count.load(Ordering::Relaxed),
+                failures: metrics.failures.load(Ordering::Relaxed),
+                latency_sum_micros: metrics.latency_sum_micros.load(Ordering::Relaxed),
+                latency_buckets: metrics
+                    .latency_buckets
+                    .iter()
+                    .map(|count| count.load(Ordering::Relaxed))
+                    .collect(),
+            })
+            .collect()
+    }
+}
+
+/// Render `stats` in the Prometheus text exposition format
+pub fn prometheus_text(stats: &Stats) -> String {
+    let mut out = String::new();
+    let gauges = [
+        ("ngram_documents", "Documents published", stats.documents),
+        ("ngram_vocabulary_terms", "Distinct terms in the index", stats.vocabulary),
+        ("ngram_tokens", "Terms indexed across all documents", stats.total_tokens),
+        ("ngram_queue_depth", "Connections waiting for a worker", stats.queue_depth),
+        ("ngram_active_workers", "Workers handling a connection", stats.active_workers),
+    ];
+    for (name, help, value) in gauges {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} gauge\n{} {}", name, name, value);
+    }
+    let _ = writeln!(
+        out,
+        "# HELP ngram_shed_connections_total Connections turned away because the queue was full\n\
+         # TYPE ngram_shed_connections_total counter\n\
+         ngram_shed_connections_total {}",
+        stats.shed
+    );
+    let _ = writeln!(
+        out,
+        "# HELP ngram_worker_panics_total Jobs that panicked on a worker\n\
+         # TYPE ngram_worker_panics_total counter\n\
+         ngram_worker_panics_total {}",
+        stats.panics
+    );
+
+    out.push_str("# HELP ngram_requests_total Requests handled\n");
+    out.push_str("# TYPE ngram_requests_total counter\n");
+    for request in &stats.requests {
+        let _ = writeln!(
+            out,
+            "ngram_requests_total{{type=\"{}\"}} {}",
+            request.kind, request.count
+        );
+    }
+    out.push_str("# HELP ngram_request_failures_total Requests that failed\n");
+    out.push_str("# TYPE ngram_request_failures_total counter\n");
+    for request in &stats.requests {
+        let _ = writeln!(
+            out,
+            "ngram_request_failures_total{{type=\"{}\"}} {}",
+            request.kind, request.failures
+        );
+    }
+
+    out.push_str("# HELP ngram_request_duration_seconds Time taken to handle requests\n");
+    out.push_str("# TYPE ngram_request_duration_seconds histogram\n");
+    for request in &stats.requests {
+        // Prometheus buckets count everything at or below their bound
+        let mut cumulative = 0;
+        for (i, count) in request.latency_buckets.iter().enumerate() {
+            cumulative += count;
+            let bound = match LATENCY_BUCKETS_MICROS.get(i) {
+                Some(&micros) => (micros as f64 / 1e6).to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "ngram_request_duration_seconds_bucket{{type=\"{}\",le=\"{}\"}} {}",
+                request.kind, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "ngram_request_duration_seconds_sum{{type=\"{}\"}} {}\n\
+             ngram_request_duration_seconds_count{{type=\"{}\"}} {}",
+            request.kind,
+            request.latency_sum_micros as f64 / 1e6,
+            request.kind,
+            request.count
+        );
+    }
+
+    out.push_str("# HELP ngram_worker_jobs_total Jobs run by each worker\n");
+    out.push_str("# TYPE ngram_worker_jobs_total counter\n");
+    for worker in &stats.workers {
+        let _ = writeln!(
+            out,
+            "ngram_worker_jobs_total{{worker=\"{}\"}} {}",
+            worker.id, worker.jobs
+        );
+    }
+    out.push_str("# HELP ngram_worker_busy_seconds_total Time each worker spent running jobs\n");
+    out.push_str("# TYPE ngram_worker_busy_seconds_total counter\n");
+    for worker in &stats.workers {
+        let _ = writeln!(
+            out,
+            "ngram_worker_busy_seconds_total{{worker=\"{}\"}} {}",
+            worker.id,
+            worker.busy_micros as f64 / 1e6
+        );
+    }
+    out
+}
@@ -1,13 +1,19 @@
 use std::borrow::Borrow;
 use std::collections::{hash_map::DefaultHasher, LinkedList};
 use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::RwLock;
 
+// How many entries `try_get` scans between calls to its check
+const CHECK_INTERVAL: usize = 1024;
+
 // The ConcurrentMultiMap struct is a concurrent hash map that allows multiple values to be
 // associated with a single key. It is implemented using a vector of RwLocks, where each lock
 // protects a linked list of key-value pairs.
 pub struct ConcurrentMultiMap<K: Hash + Eq, V> {
     buckets: Vec<RwLock<LinkedList<(K, V)>>>,
+    /// The number of distinct keys, counted by `set` as it adds the first value for each
+    keys: AtomicUsize,
 }
 
 impl<K: Hash + Eq, V> ConcurrentMultiMap<K, V> {
@@ -17,7 +23,10 @@ impl<K: Hash + Eq, V> ConcurrentMultiMap<K, V> {
         let buckets = (0..bucket_count)
             .map(|_| RwLock::new(LinkedList::new()))
             .collect();
-        ConcurrentMultiMap { buckets }
+        ConcurrentMultiMap {
+            buckets,
+            keys: AtomicUsize::new(0),
+        }
     }
 }
 
@@ -36,14 +45,22 @@ impl<K: Hash + Eq, V: Clone + Eq> ConcurrentMultiMap<K, V> {
         let mut bucket = self.buckets[bucket_index].write().unwrap();
 
         // Check if the key-value pair already exists; if not, insert it.
+        let mut new_key = true;
         for (existing_key, existing_value) in bucket.iter() {
-            if existing_key == &key && existing_value == &value {
-                return;
+            if existing_key == &key {
+                if existing_value == &value {
+                    return;
+                }
+                new_key = false;
             }
         }
 
-        // Insert the new key-value pair if it wasn't found.
+        // Insert the new key-value pair if it wasn't found. Every entry for a key is in the same
+        // bucket, so holding its lock keeps two calls from both counting the same new key.
         bucket.push_back((key, value));
+        if new_key {
+            self.keys.fetch_add(1, Ordering::Relaxed);
+        }
     }
 
     // TODO:
@@ -75,6 +92,33 @@ impl<K: Hash + Eq, V: Clone + Eq> ConcurrentMultiMap<K, V> {
             .collect()
     }
 
+    /// Like `get`, but calls `check` every `CHECK_INTERVAL` entries of the bucket it scans, and
+    /// gives up with the error `check` returns if it returns one
+    pub fn try_get<Q, E, C>(&self, key: &Q, mut check: C) -> Result<Vec<V>, E>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        C: FnMut() -> Result<(), E>,
+    {
+        let bucket_index = self.get_bucket_index(key);
+        let bucket = self.buckets[bucket_index].read().unwrap();
+        let mut values = Vec::new();
+        for (i, (existing_key, value)) in bucket.iter().enumerate() {
+            if i % CHECK_INTERVAL == 0 {
+                check()?;
+            }
+            if existing_key.borrow() == key {
+                values.push(value.clone());
+            }
+        }
+        Ok(values)
+    }
+
+    /// The number of distinct keys in the map
+    pub fn key_count(&self) -> usize {
+        self.keys.load(Ordering::Relaxed)
+    }
+
     // Helper function to calculate the bucket index for a given key.
     fn get_bucket_index<Q>(&self, key: &Q) -> usize
     where
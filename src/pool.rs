@@ -1,6 +1,19 @@
+use crossbeam_deque::{Injector, Steal, Stealer, Worker as Deque};
+use log::{debug, error, trace, warn};
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    any::Any,
+    cmp::{Ordering as CmpOrdering, Reverse},
+    collections::BinaryHeap,
+    fmt,
+    marker::PhantomData,
+    mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{fence, AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc, Condvar, Mutex, RwLock,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
 // We represent a job as a boxed closure that can be sent across threads. Since the closure is
@@ -8,98 +21,1264 @@ use std::{
 // it to other threads.
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+// The queue jobs wait in for a worker. Jobs are pushed onto shared, lock-free injector queues,
+// and each worker moves them onto a deque of its own in batches, so most of the time a worker
+// takes its next job without touching anything another worker is using. A worker that finds its
+// deque and the injectors empty steals a batch from another worker's deque. No lock is taken to
+// queue or take a job: locks are only taken to put a worker to sleep or wake one up, to wait for
+// room in a full queue, and for jobs scheduled for later.
+//
+// A worker only goes to sleep after announcing it is idle and then checking once more for work,
+// and whoever queues a job checks for idle workers after queueing it, so either the worker sees
+// the job or the submitter sees the worker. Nobody is woken while a worker is awake and looking
+// for a job, since it is bound to find the new one; the last worker to stop looking wakes another
+// if there is more to do, so a burst of jobs wakes workers one after another rather than all at
+// once. Submitters don't take the lock at all unless there is a worker to wake.
+//
+// Interactive and bulk jobs wait in separate injectors. Interactive jobs go first, but while bulk
+// jobs are waiting every few jobs a worker starts is a bulk one, so a steady stream of interactive
+// jobs can't hold them up forever.
+//
+// Jobs scheduled for later wait in a heap ordered by when they are due. There is no timer thread:
+// an idle worker sleeps no longer than it takes for the first of them to fall due, and whichever
+// worker next looks for a job after that moves it into the line of interactive jobs.
+struct Queue {
+    /// Interactive jobs, and requests for workers to retire
+    interactive: Injector<Message>,
+    bulk: Injector<Job>,
+    /// A handle to steal from each worker's deque, with the worker's ID
+    stealers: RwLock<Vec<(usize, Stealer<Message>)>>,
+    /// The number of jobs waiting, in any line. A job is counted before it is queued, so this
+    /// never drops below the number actually waiting.
+    jobs: AtomicUsize,
+    capacity: usize,
+    /// The number of workers running a job. Workers count a job as active before they stop
+    /// counting it as waiting, so it is always either queued or active.
+    active: AtomicUsize,
+    closed: AtomicBool,
+    sleep: Mutex<Sleep>,
+    /// Signalled to wake a sleeping worker
+    available: Condvar,
+    /// Sleeping workers that haven't been woken yet, as also counted under `sleep`, so a
+    /// submitter can tell there is nobody to wake without taking the lock
+    sleepy: AtomicUsize,
+    /// Workers that are awake and looking for a message
+    searching: AtomicUsize,
+    /// Held by callers of `execute` waiting for room, and by whoever tells them there is some
+    room: Mutex<()>,
+    /// Signalled when a job is taken, making room in a full queue
+    freed: Condvar,
+    /// Callers of `execute` waiting for room
+    blocked: AtomicUsize,
+    timers: Mutex<Timers>,
+    /// When the first timer falls due, in nanoseconds since `epoch`, or `NO_TIMER`
+    next_due: AtomicU64,
+    epoch: Instant,
+}
+
+struct Sleep {
+    /// Workers waiting for a message
+    idle: usize,
+    /// Workers that have been woken but haven't taken the lock again yet
+    waking: usize,
+}
+
+// A worker's end of the queue
+struct LocalQueue {
+    id: usize,
+    deque: Deque<Message>,
+    /// Interactive jobs started in a row while bulk jobs were waiting
+    streak: u32,
+}
+
+const NO_TIMER: u64 = u64::MAX;
+
+struct Timers {
+    /// Jobs that aren't due yet, earliest first
+    heap: BinaryHeap<Reverse<Timer>>,
+    /// Counts timers as they are scheduled, so ones due at the same time run in that order
+    scheduled: u64,
+}
+
+// A job scheduled by `execute_after` or `execute_every`
+struct Timer {
+    due: Instant,
+    seq: u64,
+    cancelled: Arc<AtomicBool>,
+    task: TimerTask,
+}
+enum TimerTask {
+    Once(Job),
+    Every(Arc<Periodic>),
+}
+struct Periodic {
+    f: Box<dyn Fn() + Send + Sync>,
+    interval: Duration,
+    /// Set while a run is queued or running, so a slow run isn't overlapped by the next one
+    running: AtomicBool,
+}
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+impl Eq for Timer {}
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Timer {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (self.due, self.seq).cmp(&(other.due, other.seq))
+    }
+}
+
+/// How urgently a job should be run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Priority {
+    /// Jobs someone is waiting on, such as searches. They start before bulk jobs.
+    #[default]
+    Interactive,
+    /// Long jobs that can wait, such as publishing whole books
+    Bulk,
+}
+
+// While bulk jobs are waiting, at most this many interactive jobs start between two bulk ones on
+// the same worker
+const INTERACTIVE_PER_BULK: u32 = 4;
+
+impl Timers {
+    fn next_due(&self) -> Option<Instant> {
+        self.heap.peek().map(|Reverse(timer)| timer.due)
+    }
+
+    fn push(&mut self, due: Instant, cancelled: Arc<AtomicBool>, task: TimerTask) {
+        let seq = self.scheduled;
+        self.scheduled += 1;
+        self.heap.push(Reverse(Timer {
+            due,
+            seq,
+            cancelled,
+            task,
+        }));
+    }
+
+    // Take the jobs of the timers that have fallen due, scheduling the next runs of periodic ones
+    fn take_due(&mut self, now: Instant) -> Vec<Job> {
+        let mut due_jobs = Vec::new();
+        while self.next_due().is_some_and(|due| due <= now) {
+            let Reverse(timer) = self.heap.pop().unwrap();
+            if timer.cancelled.load(Ordering::SeqCst) {
+                continue;
+            }
+            let job: Job = match timer.task {
+                TimerTask::Once(job) => job,
+                TimerTask::Every(periodic) => {
+                    // Runs keep to the schedule they started on, skipping any that are missed
+                    // while the last one is still going or the pool is too busy to start them
+                    let mut due = timer.due + periodic.interval;
+                    if due <= now {
+                        let missed = (now - due).as_nanos() / periodic.interval.as_nanos() + 1;
+                        due += periodic.interval * missed.try_into().unwrap_or(u32::MAX);
+                    }
+                    let busy = periodic.running.swap(true, Ordering::SeqCst);
+                    let next = Arc::clone(&periodic);
+                    self.push(due, timer.cancelled, TimerTask::Every(next));
+                    if busy {
+                        continue;
+                    }
+                    Box::new(move || {
+                        let _running = RunningGuard(&periodic.running);
+                        (periodic.f)();
+                    })
+                }
+            };
+            due_jobs.push(job);
+        }
+        due_jobs
+    }
+}
+
+// Clears a periodic job's `running` flag once a run ends, even if it panics
+struct RunningGuard<'a>(&'a AtomicBool);
+impl Drop for RunningGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+// What a worker gets from the queue
+enum Popped {
+    Message(Message),
+    TimedOut,
+    Closed,
+}
+
+// Keep trying `steal` for as long as it loses races with other threads
+fn retry<T>(mut steal: impl FnMut() -> Steal<T>) -> Option<T> {
+    loop {
+        match steal() {
+            Steal::Success(value) => return Some(value),
+            Steal::Empty => return None,
+            Steal::Retry => {}
+        }
+    }
+}
+
+impl Queue {
+    fn new(capacity: usize) -> Self {
+        Self {
+            interactive: Injector::new(),
+            bulk: Injector::new(),
+            stealers: RwLock::new(Vec::new()),
+            jobs: AtomicUsize::new(0),
+            capacity,
+            active: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            sleep: Mutex::new(Sleep { idle: 0, waking: 0 }),
+            available: Condvar::new(),
+            sleepy: AtomicUsize::new(0),
+            searching: AtomicUsize::new(0),
+            room: Mutex::new(()),
+            freed: Condvar::new(),
+            blocked: AtomicUsize::new(0),
+            timers: Mutex::new(Timers {
+                heap: BinaryHeap::new(),
+                scheduled: 0,
+            }),
+            next_due: AtomicU64::new(NO_TIMER),
+            epoch: Instant::now(),
+        }
+    }
+
+    // Give worker `id` a deque of its own, which other workers can steal from
+    fn join(&self, id: usize) -> LocalQueue {
+        let deque = Deque::new_fifo();
+        self.stealers.write().unwrap().push((id, deque.stealer()));
+        LocalQueue {
+            id,
+            deque,
+            streak: 0,
+        }
+    }
+
+    // Hand back what is left in a worker's deque as it exits, and stop stealing from it
+    fn leave(&self, local: LocalQueue) {
+        while let Some(message) = local.deque.pop() {
+            self.interactive.push(message);
+            self.wake_one();
+        }
+        self.stealers.write().unwrap().retain(|(id, _)| *id != local.id);
+    }
+
+    // Queue the job `f`, waiting for room if `wait` is set or handing it back if not. Once the
+    // queue is closed every job is handed back.
+    fn push_job<F>(&self, priority: Priority, f: F, wait: bool) -> Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if !self.reserve(wait) {
+            return Err(f);
+        }
+        match priority {
+            Priority::Interactive => self.interactive.push(Message::Run(Box::new(f))),
+            Priority::Bulk => self.bulk.push(Box::new(f)),
+        }
+        self.wake_one();
+        Ok(())
+    }
+
+    // Count a job that is about to be queued, waiting for room if `wait` is set. Fails if the
+    // queue is full, or closed.
+    fn reserve(&self, wait: bool) -> bool {
+        if self.try_reserve() {
+            return true;
+        }
+        if !wait || self.closed.load(Ordering::SeqCst) {
+            return false;
+        }
+        let mut room = self.room.lock().unwrap();
+        self.blocked.fetch_add(1, Ordering::SeqCst);
+        let reserved = loop {
+            if self.try_reserve() {
+                break true;
+            }
+            if self.closed.load(Ordering::SeqCst) {
+                break false;
+            }
+            room = self.freed.wait(room).unwrap();
+        };
+        self.blocked.fetch_sub(1, Ordering::SeqCst);
+        reserved
+    }
+
+    fn try_reserve(&self) -> bool {
+        !self.closed.load(Ordering::SeqCst)
+            && self
+                .jobs
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |jobs| {
+                    (jobs < self.capacity).then_some(jobs + 1)
+                })
+                .is_ok()
+    }
+
+    // Count a job a worker has just taken as running rather than waiting
+    fn start_job(&self) {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        self.jobs.fetch_sub(1, Ordering::SeqCst);
+        if self.blocked.load(Ordering::SeqCst) > 0 {
+            let _room = self.room.lock().unwrap();
+            self.freed.notify_one();
+        }
+    }
+
+    // Schedule `task` to be queued at `due`, handing it back if the queue is closed
+    fn push_timer(
+        &self,
+        due: Instant,
+        cancelled: Arc<AtomicBool>,
+        task: TimerTask,
+    ) -> Result<(), TimerTask> {
+        let mut timers = self.timers.lock().unwrap();
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(task);
+        }
+        let earliest = timers.next_due().is_none_or(|next| due < next);
+        timers.push(due, cancelled, task);
+        self.set_next_due(&timers);
+        drop(timers);
+        // A sleeping worker may be waiting for a later timer, or none at all, so have one look
+        // again at how long to sleep
+        if earliest {
+            self.wake_one();
+        }
+        Ok(())
+    }
+
+    fn push_retire(&self) {
+        self.interactive.push(Message::Retire);
+        self.wake_one();
+    }
+
+    // Wake a sleeping worker to take a message that has just been pushed, unless a worker that is
+    // awake will find it, or every sleeping worker has already been woken
+    fn wake_one(&self) {
+        // Pairs with the fence in `sleep`, so a worker going to sleep either sees the message or
+        // is seen here
+        fence(Ordering::SeqCst);
+        if self.searching.load(Ordering::SeqCst) > 0 || self.sleepy.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        let mut sleep = self.sleep.lock().unwrap();
+        if sleep.idle > sleep.waking {
+            sleep.waking += 1;
+            self.sleepy.store(sleep.idle - sleep.waking, Ordering::SeqCst);
+            drop(sleep);
+            self.available.notify_one();
+        }
+    }
+
+    fn set_next_due(&self, timers: &Timers) {
+        let next_due = timers.next_due().map_or(NO_TIMER, |due| {
+            let nanos = due.saturating_duration_since(self.epoch).as_nanos();
+            nanos.try_into().unwrap_or(NO_TIMER - 1)
+        });
+        self.next_due.store(next_due, Ordering::SeqCst);
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        match self.next_due.load(Ordering::SeqCst) {
+            NO_TIMER => None,
+            nanos => Some(self.epoch + Duration::from_nanos(nanos)),
+        }
+    }
+
+    fn timers_due(&self) -> bool {
+        self.next_due().is_some_and(|due| due <= Instant::now())
+    }
+
+    // Queue the jobs of the timers that have fallen due as interactive jobs. They don't count
+    // against the queue's capacity, as nobody is there to wait for room. The caller is looking
+    // for a job, so it takes the first, and wakes another worker for the rest once it has.
+    fn release_due_timers(&self) {
+        let mut timers = self.timers.lock().unwrap();
+        let due_jobs = timers.take_due(Instant::now());
+        self.set_next_due(&timers);
+        drop(timers);
+        for job in due_jobs {
+            self.jobs.fetch_add(1, Ordering::SeqCst);
+            self.interactive.push(Message::Run(job));
+        }
+    }
+
+    // Take the next message for a worker: from its own deque, then the injectors, then other
+    // workers' deques
+    fn find(&self, local: &mut LocalQueue) -> Option<Message> {
+        let bulk_waiting = !self.bulk.is_empty();
+        if bulk_waiting && local.streak >= INTERACTIVE_PER_BULK {
+            if let Some(job) = retry(|| self.bulk.steal()) {
+                local.streak = 0;
+                return Some(Message::Run(job));
+            }
+        }
+        let message = local.deque.pop().or_else(|| {
+            retry(|| {
+                self.interactive
+                    .steal_batch_and_pop(&local.deque)
+                    .or_else(|| self.steal_from_others(local))
+            })
+        });
+        match message {
+            Some(message) => {
+                if matches!(message, Message::Run(_)) && bulk_waiting {
+                    local.streak += 1;
+                }
+                Some(message)
+            }
+            None => {
+                let job = retry(|| self.bulk.steal())?;
+                local.streak = 0;
+                Some(Message::Run(job))
+            }
+        }
+    }
+
+    fn steal_from_others(&self, local: &LocalQueue) -> Steal<Message> {
+        let stealers = self.stealers.read().unwrap();
+        stealers
+            .iter()
+            .filter(|(id, _)| *id != local.id)
+            .map(|(_, stealer)| stealer.steal_batch_and_pop(&local.deque))
+            .collect()
+    }
+
+    // Whether anything is waiting for a worker that has found its own deque empty
+    fn has_work(&self) -> bool {
+        !self.interactive.is_empty()
+            || !self.bulk.is_empty()
+            || self.closed.load(Ordering::SeqCst)
+            || self.timers_due()
+            || self.stealers.read().unwrap().iter().any(|(_, s)| !s.is_empty())
+    }
+
+    // Take the next message for a worker, waiting at most `timeout` for one if given. Once the
+    // queue is closed, the messages left in it are still handed out before `Closed` is.
+    fn pop(&self, local: &mut LocalQueue, timeout: Option<Duration>) -> Popped {
+        let idle_deadline = timeout.map(|timeout| Instant::now() + timeout);
+        self.searching.fetch_add(1, Ordering::SeqCst);
+        loop {
+            if self.timers_due() {
+                self.release_due_timers();
+            }
+            if let Some(message) = self.find(local) {
+                // Submitters don't wake anyone while this worker is looking, so if it was the
+                // only one, have another take whatever else is waiting
+                if self.searching.fetch_sub(1, Ordering::SeqCst) == 1 {
+                    fence(Ordering::SeqCst);
+                    if self.has_work() {
+                        self.wake_one();
+                    }
+                }
+                if let Message::Run(_) = message {
+                    self.start_job();
+                }
+                return Popped::Message(message);
+            }
+            if self.closed.load(Ordering::SeqCst) {
+                self.searching.fetch_sub(1, Ordering::SeqCst);
+                return Popped::Closed;
+            }
+            if idle_deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+                self.searching.fetch_sub(1, Ordering::SeqCst);
+                return Popped::TimedOut;
+            }
+            self.sleep(idle_deadline);
+        }
+    }
+
+    // Sleep until woken, the next timer falls due, or `deadline` passes, unless there turns out
+    // to be work waiting
+    fn sleep(&self, deadline: Option<Instant>) {
+        let mut sleep = self.sleep.lock().unwrap();
+        sleep.idle += 1;
+        self.sleepy.store(sleep.idle - sleep.waking, Ordering::SeqCst);
+        self.searching.fetch_sub(1, Ordering::SeqCst);
+        // Pairs with the fence in `wake_one`
+        fence(Ordering::SeqCst);
+        if self.has_work() {
+            sleep.idle -= 1;
+            sleep.waking = sleep.waking.min(sleep.idle);
+        } else {
+            match deadline.into_iter().chain(self.next_due()).min() {
+                Some(wake_at) => {
+                    let timeout = wake_at.saturating_duration_since(Instant::now());
+                    sleep = self.available.wait_timeout(sleep, timeout).unwrap().0;
+                }
+                None => sleep = self.available.wait(sleep).unwrap(),
+            }
+            // This may be a timeout or a spurious wakeup rather than the wakeup that was
+            // counted, but then the worker that was meant is woken too, and one extra wakeup is
+            // harmless
+            sleep.idle -= 1;
+            sleep.waking = sleep.waking.saturating_sub(1).min(sleep.idle);
+        }
+        self.sleepy.store(sleep.idle - sleep.waking, Ordering::SeqCst);
+        self.searching.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // Close the queue, cancelling the jobs scheduled for later
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        let mut timers = self.timers.lock().unwrap();
+        let heap = mem::take(&mut timers.heap);
+        self.set_next_due(&timers);
+        drop(timers);
+        // Taking each lock makes sure nobody is between checking `closed` and waiting
+        drop(self.sleep.lock().unwrap());
+        self.available.notify_all();
+        drop(self.room.lock().unwrap());
+        self.freed.notify_all();
+        drop(heap);
+    }
+
+    // Close the queue and throw away everything in it, returning the number of jobs thrown away
+    fn close_and_clear(&self) -> usize {
+        let mut discarded = Vec::new();
+        while let Some(message) = retry(|| self.interactive.steal()) {
+            discarded.push(message);
+        }
+        while let Some(job) = retry(|| self.bulk.steal()) {
+            discarded.push(Message::Run(job));
+        }
+        for (_, stealer) in self.stealers.read().unwrap().iter() {
+            while let Some(message) = retry(|| stealer.steal()) {
+                discarded.push(message);
+            }
+        }
+        let jobs = discarded
+            .iter()
+            .filter(|message| matches!(message, Message::Run(_)))
+            .count();
+        self.jobs.fetch_sub(jobs, Ordering::SeqCst);
+        self.close();
+        // Dropping a job can run arbitrary code, so do it without holding any lock
+        drop(discarded);
+        jobs
+    }
+
+    fn queued(&self) -> usize {
+        self.jobs.load(Ordering::SeqCst)
+    }
+}
+
+/// The error returned by [`ThreadPool::try_execute`] when the queue is full, or the pool has been
+/// shut down. It hands the job back so the caller can deal with it some other way.
+pub struct QueueFull<F>(pub F);
+impl<F> QueueFull<F> {
+    pub fn into_inner(self) -> F {
+        self.0
+    }
+}
+impl<F> fmt::Debug for QueueFull<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("QueueFull(..)")
+    }
+}
+impl<F> fmt::Display for QueueFull<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the thread pool's queue is full")
+    }
+}
+impl<F> std::error::Error for QueueFull<F> {}
+
+/// The error returned by [`ThreadPool::shutdown`] when some workers were still running a job at
+/// the deadline. They are left to finish it in the background.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShutdownTimeout {
+    /// The IDs of the workers that hadn't finished
+    pub unfinished: Vec<usize>,
+}
+impl fmt::Display for ShutdownTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} worker(s) still running a job at the shutdown deadline: {:?}",
+            self.unfinished.len(),
+            self.unfinished
+        )
+    }
+}
+impl std::error::Error for ShutdownTimeout {}
+
+// How often `shutdown` checks whether the workers have finished
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// A handle to a job started with [`ThreadPool::spawn`], used to wait for the value it returns.
+/// Like [`thread::JoinHandle`], joining gives `Err` with the panic payload if the job panicked.
+/// Dropping the handle doesn't cancel the job; its result is just thrown away.
+pub struct JobHandle<T> {
+    result: mpsc::Receiver<thread::Result<T>>,
+}
+impl<T> JobHandle<T> {
+    /// Wait for the job to finish
+    pub fn join(self) -> thread::Result<T> {
+        self.result.recv().unwrap_or_else(|_| Err(Box::new(JOB_DROPPED)))
+    }
+
+    /// Wait at most `timeout` for the job to finish, handing the handle back if it hasn't
+    pub fn join_timeout(self, timeout: Duration) -> Result<thread::Result<T>, JobHandle<T>> {
+        match self.result.recv_timeout(timeout) {
+            Ok(result) => Ok(result),
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(self),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Ok(Err(Box::new(JOB_DROPPED))),
+        }
+    }
+}
+impl<T> fmt::Debug for JobHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("JobHandle(..)")
+    }
+}
+
+/// A job scheduled with [`ThreadPool::execute_after`] or [`ThreadPool::execute_every`]. Dropping
+/// the handle leaves the job scheduled.
+#[derive(Debug, Clone)]
+pub struct TimerHandle {
+    cancelled: Arc<AtomicBool>,
+}
+impl TimerHandle {
+    /// Stop the job from running again. A run that has already started carries on.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+// The panic payload reported if a job was thrown away without being run
+const JOB_DROPPED: &str = "the job was dropped before it finished";
+
+// What a worker can be sent. Workers are told to retire through the same queue as jobs, so a
+// worker removed by `resize` always finishes the job it is running first.
+enum Message {
+    Run(Job),
+    Retire,
+}
+
+/// How long a worker above the minimum size waits for a job before retiring, unless configured
+/// otherwise
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// The state every worker shares with the pool
+struct Shared {
+    queue: Queue,
+    /// The number of jobs that have panicked
+    panics: AtomicU64,
+    /// The number of workers that haven't been told to retire
+    size: AtomicUsize,
+    min_size: usize,
+    max_size: usize,
+    idle_timeout: Duration,
+    /// Worker threads are named this followed by `-` and the worker's ID
+    thread_name: String,
+    stack_size: Option<usize>,
+}
+impl Shared {
+    fn autoscales(&self) -> bool {
+        self.max_size > self.min_size
+    }
+
+    // Give up a place in the pool if it is bigger than it needs to be
+    fn try_shrink(&self) -> bool {
+        self.size
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |size| {
+                (size > self.min_size).then(|| size - 1)
+            })
+            .is_ok()
+    }
+}
+
+/// What one worker has done since it started
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkerStats {
+    /// The worker's ID, which is also the number at the end of its thread's name
+    pub id: usize,
+    /// The number of jobs it has run
+    pub jobs: u64,
+    /// The total time it has spent running jobs
+    pub busy: Duration,
+    /// The number of its jobs that panicked
+    pub panics: u64,
+}
+
+// The counters behind `WorkerStats`, updated by the worker and read by the pool
+#[derive(Default)]
+struct WorkerCounters {
+    jobs: AtomicU64,
+    busy_micros: AtomicU64,
+    panics: AtomicU64,
+}
+
 struct Worker {
     id: usize,
     thread: Option<thread::JoinHandle<()>>,
+    counters: Arc<WorkerCounters>,
 }
 impl Worker {
-    // TODO:
-    // Spawn a new thread that will loop forever, receiving jobs from the receiver and executing
-    // them. If the `recv()` method returns an error, it means the thread pool has been dropped and
-    // the thread should exit by breaking the loop.
-    // This function should return a `Worker` as a handle to the thread.
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || {
+    fn new(id: usize, shared: Arc<Shared>) -> Worker {
+        let counters = Arc::new(WorkerCounters::default());
+        let mut builder = thread::Builder::new().name(format!("{}-{}", shared.thread_name, id));
+        if let Some(stack_size) = shared.stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+        let worker_counters = Arc::clone(&counters);
+        let mut local = shared.queue.join(id);
+        let thread = builder.spawn(move || {
+            let counters = worker_counters;
             loop {
-                let job = receiver.lock().unwrap().recv();
-                match job {
-                    Ok(job) => {
-                        println!("Worker {} got a job; executing.", id);
-                        job();
+                let timeout = shared.autoscales().then_some(shared.idle_timeout);
+                match shared.queue.pop(&mut local, timeout) {
+                    Popped::Message(Message::Run(job)) => {
+                        trace!("Worker {} got a job; executing.", id);
+                        let started = Instant::now();
+                        // A panicking job must not take the worker down with it, or every bad
+                        // request would leave the pool one thread smaller
+                        let result = panic::catch_unwind(AssertUnwindSafe(job));
+                        shared.queue.active.fetch_sub(1, Ordering::SeqCst);
+                        let busy = started.elapsed().as_micros().try_into().unwrap_or(u64::MAX);
+                        counters.jobs.fetch_add(1, Ordering::Relaxed);
+                        counters.busy_micros.fetch_add(busy, Ordering::Relaxed);
+                        if let Err(payload) = result {
+                            counters.panics.fetch_add(1, Ordering::Relaxed);
+                            shared.panics.fetch_add(1, Ordering::Relaxed);
+                            error!(
+                                "Worker {} caught a panic in a job: {}",
+                                id,
+                                panic_message(payload.as_ref())
+                            );
+                        }
                     },
-                    Err(_) => {
-                        println!("Worker {} disconnected; shutting down.", id);
+                    Popped::Message(Message::Retire) => {
+                        debug!("Worker {} retired; shutting down.", id);
+                        break;
+                    }
+                    Popped::TimedOut => {
+                        if shared.try_shrink() {
+                            debug!("Worker {} was idle; shutting down.", id);
+                            break;
+                        }
+                    }
+                    Popped::Closed => {
+                        debug!("Worker {} disconnected; shutting down.", id);
                         break;
                     }
                 }
             }
+            shared.queue.leave(local);
         });
 
         Worker {
             id,
-            thread: Some(thread),
+            thread: Some(thread.expect("failed to spawn a worker thread")),
+            counters,
+        }
+    }
+
+    fn stats(&self) -> WorkerStats {
+        WorkerStats {
+            id: self.id,
+            jobs: self.counters.jobs.load(Ordering::Relaxed),
+            busy: Duration::from_micros(self.counters.busy_micros.load(Ordering::Relaxed)),
+            panics: self.counters.panics.load(Ordering::Relaxed),
+        }
+    }
+
+    fn join(&mut self) {
+        // Take ownership of the thread handle and join it. Jobs can't panic the worker, but
+        // panicking here would abort if the pool is being dropped during a panic already.
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Worker {} panicked", self.id);
+            }
         }
     }
 }
 
+// The message a panic was raised with, if it was raised with a string as `panic!` usually is
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "(non-string panic payload)"
+    }
+}
+
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    /// Every worker thread that hasn't been joined, including retired ones that may still be
+    /// finishing a job
+    workers: Mutex<Vec<Worker>>,
+    next_id: AtomicUsize,
+    shared: Arc<Shared>,
 }
 
 impl ThreadPool {
-    // TODO:
-    // Spawn `size` workers by calling the `Worker::new` function `size` times, each time with a
-    // unique id. You will need to create a channel and wrap the receiver in an `Arc<Mutex<...>>`
-    // in order to share it with the worker threads. Finally, return an instance of `ThreadPool`
-    // that has the workers and the sender.
     pub fn new(size: usize) -> ThreadPool {
-        assert!(size > 0);
+        Self::builder().size(size).build()
+    }
 
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
+    /// Create a pool of `size` workers whose queue holds at most `capacity` jobs that no worker
+    /// has picked up yet. Once it is full, `execute` blocks and `try_execute` fails.
+    pub fn with_queue_capacity(size: usize, capacity: usize) -> ThreadPool {
+        Self::builder().size(size).queue_capacity(capacity).build()
+    }
+
+    /// Start configuring a pool
+    pub fn builder() -> ThreadPoolBuilder {
+        ThreadPoolBuilder {
+            size: 1,
+            max_size: None,
+            queue_capacity: usize::MAX,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            thread_name: "ngram-worker".to_string(),
+            stack_size: None,
+        }
+    }
+
+    /// The number of workers, not counting ones that have been told to retire but are still
+    /// finishing a job
+    pub fn size(&self) -> usize {
+        self.shared.size.load(Ordering::SeqCst)
+    }
 
-        let mut workers = Vec::with_capacity(size);
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+    /// Change the number of workers to `size`. New workers start straight away; workers that are
+    /// removed finish the job they are running, and any jobs queued before the call, first.
+    ///
+    /// An autoscaling pool carries on scaling from the new size, growing only while it is below
+    /// its maximum size and shrinking only while it is above its minimum.
+    pub fn resize(&self, size: usize) {
+        assert!(size > 0);
+        let mut workers = self.workers.lock().unwrap();
+        reap(&mut workers);
+        if self.is_shut_down() {
+            return;
+        }
+        let current = self.shared.size.swap(size, Ordering::SeqCst);
+        if size > current {
+            debug!("Growing the pool from {} to {} workers", current, size);
+            for _ in current..size {
+                workers.push(self.new_worker());
+            }
+        } else if size < current {
+            debug!("Shrinking the pool from {} to {} workers", current, size);
+            for _ in size..current {
+                self.shared.queue.push_retire();
+            }
         }
+    }
 
-        ThreadPool {
-            workers,
-            sender: Some(sender),
+    /// Stop taking jobs and wait up to `timeout` for the workers to finish the jobs that are
+    /// queued or running. Workers that are still busy at the deadline are reported and left
+    /// running; dropping the pool afterwards doesn't wait for them.
+    ///
+    /// Once the pool has been shut down, `execute` drops the jobs it is given and `try_execute`
+    /// hands them back.
+    pub fn shutdown(&self, timeout: Duration) -> Result<(), ShutdownTimeout> {
+        self.shared.queue.close();
+        let deadline = Instant::now() + timeout;
+        let mut workers = self.workers.lock().unwrap();
+        loop {
+            reap(&mut workers);
+            let now = Instant::now();
+            if workers.is_empty() {
+                return Ok(());
+            }
+            if now >= deadline {
+                break;
+            }
+            thread::sleep(SHUTDOWN_POLL_INTERVAL.min(deadline - now));
         }
+        // Dropping a worker without joining its thread detaches it
+        let unfinished = workers.drain(..).map(|worker| worker.id).collect();
+        Err(ShutdownTimeout { unfinished })
     }
 
-    // TODO:
-    // Send the job `f` to the worker threads via the channel `send` method.
+    /// Stop taking jobs and throw away the ones that are queued, returning how many there were.
+    /// Jobs that are already running carry on; `shutdown` can wait for them.
+    pub fn shutdown_now(&self) -> usize {
+        self.shared.queue.close_and_clear()
+    }
+
+    fn is_shut_down(&self) -> bool {
+        self.shared.queue.closed.load(Ordering::SeqCst)
+    }
+
+    fn new_worker(&self) -> Worker {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        Worker::new(id, Arc::clone(&self.shared))
+    }
+
+    // Add a worker if an autoscaling pool has more jobs waiting than idle workers to take them
+    fn scale_up(&self) {
+        if !self.shared.autoscales() {
+            return;
+        }
+        let idle = self.size().saturating_sub(self.active());
+        if self.queued() <= idle {
+            return;
+        }
+        // Hold the lock while growing so `resize` never sees a size without its workers
+        let mut workers = self.workers.lock().unwrap();
+        let grown = self
+            .shared
+            .size
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |size| {
+                (size < self.shared.max_size).then(|| size + 1)
+            });
+        if let Ok(size) = grown {
+            debug!("Jobs are waiting; growing the pool to {} workers", size + 1);
+            reap(&mut workers);
+            workers.push(self.new_worker());
+        }
+    }
+
+    /// The number of jobs waiting for a worker
+    pub fn queued(&self) -> usize {
+        self.shared.queue.queued()
+    }
+
+    /// The number of workers running a job
+    pub fn active(&self) -> usize {
+        self.shared.queue.active.load(Ordering::SeqCst)
+    }
+
+    /// What each worker has done, in order of ID. Workers that have retired are left out.
+    pub fn worker_stats(&self) -> Vec<WorkerStats> {
+        let workers = self.workers.lock().unwrap();
+        workers
+            .iter()
+            .filter(|worker| worker.thread.as_ref().is_some_and(|t| !t.is_finished()))
+            .map(Worker::stats)
+            .collect()
+    }
+
+    /// The number of jobs that have panicked. The workers that ran them carry on with the next job.
+    pub fn panics(&self) -> u64 {
+        self.shared.panics.load(Ordering::Relaxed)
+    }
+
+    /// The most jobs that can wait for a worker at once
+    pub fn queue_capacity(&self) -> usize {
+        self.shared.queue.capacity
+    }
+
+    /// Run `f` on the pool as an interactive job, waiting for room in the queue if it is full
     pub fn execute<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        if let Some(sender) = &self.sender {
-            let job = Box::new(f);
-            sender.send(job).unwrap();
+        self.execute_with_priority(Priority::Interactive, f)
+    }
+
+    /// Run `f` on the pool with the given priority, waiting for room in the queue if it is full
+    pub fn execute_with_priority<F>(&self, priority: Priority, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.shared.queue.push_job(priority, f, true) {
+            Ok(()) => self.scale_up(),
+            Err(_) => warn!("Dropping a job given to a pool that has been shut down"),
         }
     }
+
+    /// Run `f` on the pool, returning a handle that can be joined to get its result. A panic in
+    /// `f` is caught and handed to whoever joins the handle rather than taking down the worker.
+    pub fn spawn<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        self.execute(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            // The handle may have been dropped; nobody wants the result then
+            let _ = sender.send(result);
+        });
+        JobHandle { result: receiver }
+    }
+
+    /// Queue the job `f` if there is room for it, or hand it back if the queue is full
+    pub fn try_execute<F>(&self, f: F) -> Result<(), QueueFull<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.try_execute_with_priority(Priority::Interactive, f)
+    }
+
+    /// Queue the job `f` with the given priority if there is room for it, or hand it back if the
+    /// queue is full
+    pub fn try_execute_with_priority<F>(&self, priority: Priority, f: F) -> Result<(), QueueFull<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared.queue.push_job(priority, f, false).map_err(QueueFull)?;
+        self.scale_up();
+        Ok(())
+    }
+
+    /// Run `f` on the pool as an interactive job once `delay` has passed. Shutting the pool
+    /// down cancels it if it hasn't been queued yet.
+    pub fn execute_after<F>(&self, delay: Duration, f: F) -> TimerHandle
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.schedule(delay, TimerTask::Once(Box::new(f)))
+    }
+
+    /// Run `f` on the pool as an interactive job every `interval`, starting one interval from
+    /// now, until the handle is cancelled or the pool is shut down. A run that is due while the
+    /// last one is still going is skipped rather than run alongside it, and a run that panics
+    /// doesn't stop the next.
+    pub fn execute_every<F>(&self, interval: Duration, f: F) -> TimerHandle
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        assert!(!interval.is_zero());
+        let periodic = Periodic {
+            f: Box::new(f),
+            interval,
+            running: AtomicBool::new(false),
+        };
+        self.schedule(interval, TimerTask::Every(Arc::new(periodic)))
+    }
+
+    fn schedule(&self, delay: Duration, task: TimerTask) -> TimerHandle {
+        let handle = TimerHandle {
+            cancelled: Arc::new(AtomicBool::new(false)),
+        };
+        let due = Instant::now() + delay;
+        let cancelled = Arc::clone(&handle.cancelled);
+        if self.shared.queue.push_timer(due, cancelled, task).is_err() {
+            warn!("Dropping a job scheduled on a pool that has been shut down");
+            handle.cancel();
+        }
+        handle
+    }
 }
 
-impl Drop for ThreadPool {
-    // TODO:
-    // First, take ownership of the sender from inside the option, then drop it. This will trigger
-    // the worker threads to stop since the channel is closed, so you should then call `join` on
-    // each worker thread handle to make sure they finish executing. Calling `join` will also
-    // require you to take ownership of the worker thread handle from inside the option.
+impl ThreadPool {
+    /// Run `f` with a [`Scope`] whose jobs may borrow anything that outlives the call, like
+    /// [`thread::scope`] but running the jobs on the pool. Every job spawned in the scope has
+    /// finished by the time this returns. If `f` or any of the jobs panicked, the panic is
+    /// carried on here once they have all finished.
+    ///
+    /// This waits for the scope's jobs, so calling it from a job on the same pool can deadlock
+    /// if every worker ends up waiting for jobs that no worker is left to run.
+    pub fn scope<'env, F, T>(&self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        let scope = Scope {
+            pool: self,
+            state: Arc::new(ScopeState {
+                pending: Mutex::new(0),
+                done: Condvar::new(),
+                panic: Mutex::new(None),
+            }),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+
+        let mut pending = scope.state.pending.lock().unwrap();
+        while *pending > 0 {
+            pending = scope.state.done.wait(pending).unwrap();
+        }
+        drop(pending);
+
+        let job_panic = scope.state.panic.lock().unwrap().take();
+        match (result, job_panic) {
+            (Err(payload), _) | (Ok(_), Some(payload)) => panic::resume_unwind(payload),
+            (Ok(result), None) => result,
+        }
+    }
+}
+
+/// Jobs spawned through a scope may borrow from outside it. See [`ThreadPool::scope`].
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'scope ThreadPool,
+    state: Arc<ScopeState>,
+    // Invariant in both lifetimes, as `thread::Scope` is, so a job can't be handed a borrow that
+    // ends before the scope does
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+struct ScopeState {
+    /// The number of the scope's jobs that haven't finished
+    pending: Mutex<usize>,
+    done: Condvar,
+    /// The first panic raised by one of the scope's jobs
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+impl<'scope> Scope<'scope, '_> {
+    /// Run `f` on the pool as an interactive job
+    pub fn spawn<F>(&'scope self, f: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        self.spawn_with_priority(Priority::Interactive, f)
+    }
+
+    /// Run `f` on the pool with the given priority
+    pub fn spawn_with_priority<F>(&'scope self, priority: Priority, f: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        *self.state.pending.lock().unwrap() += 1;
+        let scoped = ScopedJob {
+            f: Some(f),
+            state: Arc::clone(&self.state),
+        };
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || scoped.run());
+        // SAFETY: `ThreadPool::scope` doesn't return until `pending` is back to zero, which
+        // happens only once `f` and everything it borrows for 'scope have been dropped, so the job
+        // never uses a borrow after it has ended even though the pool's queue requires 'static
+        let job = unsafe { mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Job>(job) };
+        let queue = &self.pool.shared.queue;
+        match queue.push_job(priority, job, true) {
+            Ok(()) => self.pool.scale_up(),
+            // The pool has been shut down; the scope still has to finish its work
+            Err(job) => job(),
+        }
+    }
+}
+
+// A job spawned in a scope. It counts as pending until it is dropped, whether it ran or was thrown
+// away by `shutdown_now`, so the scope can't return while anything it borrows might still be used.
+struct ScopedJob<F: FnOnce()> {
+    f: Option<F>,
+    state: Arc<ScopeState>,
+}
+impl<F: FnOnce()> ScopedJob<F> {
+    fn run(mut self) {
+        let f = self.f.take().expect("scoped jobs run once");
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+            self.state.panic.lock().unwrap().get_or_insert(payload);
+        }
+    }
+}
+impl<F: FnOnce()> Drop for ScopedJob<F> {
     fn drop(&mut self) {
-        // Close the channel by taking and dropping the sender
-        drop(self.sender.take());
+        drop(self.f.take());
+        let mut pending = self.state.pending.lock().unwrap();
+        *pending -= 1;
+        if *pending == 0 {
+            self.state.done.notify_all();
+        }
+    }
+}
+
+// Join the workers that have retired, so their handles don't pile up as the pool grows and shrinks
+fn reap(workers: &mut Vec<Worker>) {
+    workers.retain_mut(|worker| {
+        let finished = worker.thread.as_ref().is_none_or(|t| t.is_finished());
+        if finished {
+            worker.join();
+        }
+        !finished
+    });
+}
 
-        for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
+/// Configures a [`ThreadPool`]
+#[derive(Debug, Clone)]
+pub struct ThreadPoolBuilder {
+    size: usize,
+    max_size: Option<usize>,
+    queue_capacity: usize,
+    idle_timeout: Duration,
+    thread_name: String,
+    stack_size: Option<usize>,
+}
+impl ThreadPoolBuilder {
+    /// The number of workers to start with, and the fewest an autoscaling pool shrinks to.
+    /// Defaults to 1.
+    pub fn size(mut self, size: usize) -> Self {
+        self.size = size;
+        self
+    }
+    /// Let the pool grow up to `max_size` workers while jobs are waiting for one. Workers above
+    /// the starting size retire again once they have been idle for the idle timeout.
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+    /// The most jobs that may wait for a worker. Unbounded by default.
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        self.queue_capacity = capacity;
+        self
+    }
+    /// How long a worker waits for a job before an autoscaling pool retires it
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+    /// Name worker threads `<name>-<id>`, so they can be told apart in debuggers and profilers.
+    /// Defaults to `ngram-worker`.
+    pub fn thread_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.thread_name = name.into();
+        self
+    }
+    /// The size of each worker thread's stack, in bytes. Defaults to the platform's default for
+    /// new threads.
+    pub fn stack_size(mut self, bytes: usize) -> Self {
+        self.stack_size = Some(bytes);
+        self
+    }
 
-            // Take ownership of the thread handle and join it
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
-            }
+    pub fn build(self) -> ThreadPool {
+        assert!(self.size > 0);
+        assert!(self.queue_capacity > 0);
+        let max_size = self.max_size.unwrap_or(self.size);
+        assert!(max_size >= self.size);
+
+        let shared = Arc::new(Shared {
+            queue: Queue::new(self.queue_capacity),
+            panics: AtomicU64::new(0),
+            size: AtomicUsize::new(self.size),
+            min_size: self.size,
+            max_size,
+            idle_timeout: self.idle_timeout,
+            thread_name: self.thread_name,
+            stack_size: self.stack_size,
+        });
+
+        let pool = ThreadPool {
+            workers: Mutex::new(Vec::with_capacity(max_size)),
+            next_id: AtomicUsize::new(0),
+            shared,
+        };
+        let workers = (0..self.size).map(|_| pool.new_worker()).collect();
+        *pool.workers.lock().unwrap() = workers;
+        pool
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // Close the queue; workers finish the jobs left in it and then exit. This waits for
+        // them however long they take, unless `shutdown` has already given up on them.
+        self.shared.queue.close();
+
+        let workers = self.workers.get_mut().unwrap_or_else(|e| e.into_inner());
+        for worker in workers {
+            debug!("Shutting down worker {}", worker.id);
+            worker.join();
         }
     }
 }
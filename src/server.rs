@@ -1,81 +1,864 @@
-use crate::database::Database;
+use crate::cancel::{CancellationToken, Cancelled};
+use crate::config::ServerConfig;
+use crate::database::{text_range, Database};
+use crate::hangup::PeerWatcher;
+use crate::http::{HttpRequest, HttpResponse};
 use crate::message::*;
-use crate::pool::ThreadPool;
-use std::io::Write;
-use std::net::{TcpListener, TcpStream};
+use crate::metrics::{prometheus_text, Metrics, RequestKind};
+use crate::pool::{Priority, QueueFull, ThreadPool};
+use crate::slowlog::SlowQueryLog;
+use crate::transport::{Listener, Stream};
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, ToSocketAddrs};
+#[cfg(unix)]
+use std::path::Path;
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    Arc, Condvar, Mutex,
 };
-use std::thread;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-/// The number of workers in the server's thread pool
-const WORKERS: usize = 16;
+/// The number of workers in the server's thread pool unless configured otherwise
+pub const DEFAULT_WORKERS: usize = 16;
+/// How often a listener checks whether the server has been stopped while waiting for connections
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// The most unread bytes thrown away when turning a connection away
+const MAX_DISCARD_BYTES: u64 = 64 * 1024;
+/// How long idle workers are given to exit once the server has stopped
+const WORKER_EXIT_GRACE: Duration = Duration::from_millis(200);
 
 // TODO:
 // Implement the `process_message` function. This function should take a `ServerState`, a `Request`,
-// and a `TcpStream`. It should process the request and write the response to the stream.
+// and a `Stream`. It should process the request and write the response to the stream.
 // Processing the request should simply require calling the appropriate function on the database
 // and then creating the appropriate response and turning it into bytes which are sent to along
 // the stream by calling the `write_all` method.
-fn process_message(state: Arc<ServerState>, request: Request, mut stream: TcpStream) {
+//
+// The request is abandoned once `token` is cancelled, which happens when its deadline passes or,
+// for requests that can take a while, when the client hangs up.
+fn process_message(
+    state: Arc<ServerState>,
+    request: Request,
+    token: CancellationToken,
+    codec: &dyn Codec,
+    mut stream: Stream,
+    peer: &str,
+) {
+    let started = Instant::now();
+    let kind = RequestKind::of(&request);
+    let summary = summarize(&request);
+    let watch = match request {
+        Request::Search { .. } | Request::RetrieveStream { .. } => {
+            Some(state.peers.watch(&stream, &token))
+        }
+        _ => None,
+    };
+    let (outcome, result_size) = match request {
+        Request::RetrieveStream {
+            id,
+            offset,
+            chunk_size,
+        } => {
+            // Streams write their own sequence of responses
+            let sent = stream_document(&state, id, offset, chunk_size, &token, codec, &mut stream);
+            state.metrics.record(kind, started.elapsed(), sent.is_some());
+            match sent {
+                Some(bytes) => ("ok", bytes),
+                None => ("failure", 0),
+            }
+        }
+        request => {
+            let response = execute_request(&state, request, &token);
+
+            // Send the response in the same encoding the request arrived in, unless there is
+            // nobody left to read it
+            if token.check() == Err(Cancelled::Requested) {
+                info!("{} hung up before its request was answered", peer);
+                ("cancelled", 0)
+            } else {
+                if let Ok(()) = stream.write_all(&codec.encode_response(&response)) {
+                    let _ = stream.flush();
+                } else {
+                    warn!("Failed to send response to {}", peer);
+                }
+                (outcome(&response), result_size(&response))
+            }
+        }
+    };
+    drop(watch);
+
+    let elapsed = record_if_slow(&state, peer, kind, summary, result_size, started);
+    log_request(kind.name(), peer, elapsed, outcome);
+}
+
+// Add a request that began at `started` to the slow query log if it took long enough to count,
+// returning how long it took.
+fn record_if_slow(
+    state: &ServerState,
+    peer: &str,
+    kind: RequestKind,
+    summary: String,
+    result_size: u64,
+    started: Instant,
+) -> Duration {
+    let elapsed = started.elapsed();
+    if state.slow_queries.is_slow(elapsed) {
+        state.slow_queries.record(SlowQuery {
+            finished_at_ms: unix_millis(),
+            peer: peer.to_string(),
+            kind: kind.name().to_string(),
+            summary,
+            result_size,
+            duration_micros: elapsed.as_micros().try_into().unwrap_or(u64::MAX),
+        });
+    }
+    elapsed
+}
+
+// A short description of a request for the slow query log, leaving out document text
+fn summarize(request: &Request) -> String {
+    match request {
+        Request::Publish { doc } => format!("publish {} bytes", doc.len()),
+        Request::Search { word } => format!("search {:?}", word),
+        Request::Retrieve { id, offset, len } => match len {
+            Some(len) => format!("retrieve {} from {} for {} bytes", id, offset, len),
+            None => format!("retrieve {} from {}", id, offset),
+        },
+        Request::RetrieveStream {
+            id,
+            offset,
+            chunk_size,
+        } => format!("stream {} from {} in {} byte chunks", id, offset, chunk_size),
+        Request::Stats => "stats".to_string(),
+        Request::Admin { command, .. } => format!("admin {:?}", command),
+        Request::Deadline {
+            timeout_ms,
+            request,
+        } => format!("{} within {} ms", summarize(request), timeout_ms),
+    }
+}
+
+// The number of IDs or bytes of text in a response
+fn result_size(response: &Response) -> u64 {
+    match response {
+        Response::PublishSuccess(_) => 1,
+        Response::SearchSuccess(ids) => ids.len() as u64,
+        Response::RetrieveSuccess(text) | Response::RetrieveChunk(text) => text.len() as u64,
+        Response::SlowQueries(queries) => queries.len() as u64,
+        Response::RetrieveEnd
+        | Response::Failure
+        | Response::Error(_)
+        | Response::Stats(_)
+        | Response::Ok
+        | Response::Health(_)
+        | Response::Ready(_) => 0,
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+// A short description of how a request went, for the request log
+fn outcome(response: &Response) -> &'static str {
+    match response {
+        Response::Failure => "failure",
+        Response::Error(ErrorCode::Overloaded) => "overloaded",
+        Response::Error(ErrorCode::Unauthorized) => "unauthorized",
+        Response::Error(ErrorCode::Timeout) => "timeout",
+        _ => "ok",
+    }
+}
+
+// Log one line per handled request. The details are key-value pairs, so the JSON log format
+// turns them into separate fields.
+fn log_request(kind: &str, peer: &str, elapsed: Duration, outcome: &str) {
+    let latency_us = elapsed.as_micros() as u64;
+    info!(kind, peer, latency_us, outcome; "Handled request");
+}
+
+// Apply a request to the database and build the response to it, recording how long it took. This
+// is the single place requests are routed to database operations, shared by the binary/JSON
+// protocol and the HTTP gateway. Streaming requests produce more than one response and are handled
+// by `stream_document` instead.
+//
+// Once `cancel` is cancelled the request is answered with a `Timeout` error. That is also what a
+// request whose client has hung up gets, but nobody is left to read it then.
+fn execute_request(state: &ServerState, request: Request, cancel: &CancellationToken) -> Response {
+    let started = Instant::now();
+    let kind = RequestKind::of(&request);
     let response = match request {
+        _ if cancel.is_cancelled() => Response::Error(ErrorCode::Timeout),
+        Request::Publish { doc } if doc.len() as u64 > state.config.limits.max_document_bytes => {
+            Response::Failure
+        }
         Request::Publish { doc } => {
             // Publish the document and get its ID
-            let id = state.database.publish(doc);
-            Response::PublishSuccess(id)
+            match state.database.publish_cancellable(doc, cancel) {
+                Ok(id) => Response::PublishSuccess(id),
+                Err(_) => Response::Error(ErrorCode::Timeout),
+            }
         }
         Request::Search { word } => {
             // Search for documents containing the word
-            let doc_ids = state.database.search(&word);
-            Response::SearchSuccess(doc_ids)
+            match state.database.search_cancellable(&word, cancel) {
+                Ok(doc_ids) => Response::SearchSuccess(doc_ids),
+                Err(_) => Response::Error(ErrorCode::Timeout),
+            }
         }
-        Request::Retrieve { id } => {
-            // Retrieve the document with the given ID
-            match state.database.retrieve(id) {
+        Request::Retrieve { id, offset, len } => {
+            // Retrieve the requested range of the document with the given ID
+            match state.database.retrieve_range(id, offset, len) {
                 Some(doc) => Response::RetrieveSuccess(doc),
-                None => Response::Failure
+                None => Response::Failure,
+            }
+        }
+        Request::RetrieveStream { .. } => Response::Failure,
+        Request::Stats => Response::Stats(state.stats()),
+        Request::Admin { token, command } => execute_admin(state, token, command),
+        // Deadlines are unwrapped into a token when the request is read
+        Request::Deadline { .. } => Response::Failure,
+    };
+    let succeeded = !matches!(response, Response::Failure | Response::Error(_));
+    state.metrics.record(kind, started.elapsed(), succeeded);
+    response
+}
+
+// Carry out an admin request. Health and readiness checks are open to anyone, so supervisors and
+// load balancers can probe the server; everything else needs the configured admin token, and is
+// refused outright when no token is configured.
+fn execute_admin(state: &ServerState, token: Option<String>, command: AdminCommand) -> Response {
+    match command {
+        AdminCommand::Health => {
+            return Response::Health(Health {
+                uptime_ms: state.started.elapsed().as_millis() as u64,
+                in_flight: state.in_flight.load(Ordering::SeqCst) as u64,
+            })
+        }
+        AdminCommand::Ready => return Response::Ready(state.is_ready()),
+        _ => {}
+    }
+    if !state.is_admin(token.as_deref()) {
+        warn!("Refusing unauthorized admin request {:?}", command);
+        return Response::Error(ErrorCode::Unauthorized);
+    }
+    match command {
+        AdminCommand::SlowQueries { limit } => {
+            Response::SlowQueries(state.slow_queries.recent(limit as usize))
+        }
+        AdminCommand::Shutdown { graceful } => {
+            info!("Shutting down at the request of an admin");
+            state.stop(graceful);
+            Response::Ok
+        }
+        AdminCommand::Flush => {
+            // Documents only live in memory, so the only buffered output is the log
+            log::logger().flush();
+            Response::Ok
+        }
+        AdminCommand::Health | AdminCommand::Ready => unreachable!("handled above"),
+    }
+}
+
+// Send the document with the given ID as a series of `RetrieveChunk` responses followed by a
+// `RetrieveEnd`, or a single `Failure` if the document or offset doesn't exist, returning the
+// number of bytes of text sent if the whole document was sent. The document is
+// shared with the database rather than copied, and each chunk is written as soon as it is cut, so
+// neither side ever has to hold more than one chunk of it. If `token` is cancelled part way
+// through, the stream ends with a `Timeout` error instead of `RetrieveEnd`.
+fn stream_document<W: Write>(
+    state: &ServerState,
+    id: DocId,
+    mut offset: u64,
+    chunk_size: u32,
+    token: &CancellationToken,
+    codec: &dyn Codec,
+    stream: &mut W,
+) -> Option<u64> {
+    let doc = match state.database.retrieve_shared(id) {
+        Some(doc) if chunk_size > 0 && text_range(&doc, offset, Some(0)).is_some() => doc,
+        _ => {
+            let _ = stream.write_all(&codec.encode_response(&Response::Failure));
+            let _ = stream.flush();
+            return None;
+        }
+    };
+
+    let start = offset;
+    while offset < doc.len() as u64 {
+        match token.check() {
+            Ok(()) => {}
+            Err(Cancelled::Requested) => return None,
+            Err(Cancelled::DeadlinePassed) => {
+                warn!("Deadline passed while streaming document {}", id);
+                let timeout = Response::Error(ErrorCode::Timeout);
+                let _ = stream.write_all(&codec.encode_response(&timeout));
+                let _ = stream.flush();
+                return None;
             }
         }
+        // The offset always lands on a character boundary, since it advances by whole chunks
+        let range = text_range(&doc, offset, Some(chunk_size as u64)).unwrap();
+        let chunk = Response::RetrieveChunk(doc[range.clone()].to_string());
+        if stream.write_all(&codec.encode_response(&chunk)).is_err() {
+            warn!("Failed to stream document {} to client", id);
+            return None;
+        }
+        offset = range.end as u64;
+    }
+    let _ = stream.write_all(&codec.encode_response(&Response::RetrieveEnd));
+    let _ = stream.flush();
+    Some(offset - start)
+}
+
+// Read a single request from a newly accepted connection and answer it. The codec is chosen by
+// peeking at the first byte the client sends, and the response is written with the same codec.
+fn handle_connection(state: Arc<ServerState>, mut stream: Stream, peer: String) {
+    let mut reader = match await_request(&state, &mut stream) {
+        Ok(reader) => reader,
+        Err(e) => return log_unanswered(&peer, &e),
     };
+    let codec = detect_codec(reader.buffer()[0]);
+    // Stop reading at the size limit, rather than buffering a hostile frame in full
+    let max_bytes = codec.max_request_bytes(state.config.limits.max_document_bytes);
+    let mut limited = (&mut reader).take(max_bytes);
+    let decoded = codec.decode_request(&mut limited);
+    let too_large = limited.limit() == 0;
+    drop(reader);
 
-    // Send the response using the to_bytes() method
-    if let Ok(()) = stream.write_all(&response.to_bytes()) {
-        let _ = stream.flush();
+    let (request, timeout) = match decoded {
+        Ok(request) => request.into_parts(),
+        Err(DecodeError::Io(e)) if is_timeout(&e) => return log_unanswered(&peer, &e),
+        Err(e) => {
+            if too_large {
+                warn!("Request from {} is larger than {} bytes", peer, max_bytes);
+            } else {
+                warn!("Failed to parse {} request from {}: {}", codec.name(), peer, e);
+            }
+            // Send failure response in case of invalid request
+            let failure_response = Response::Failure;
+            let _ = stream.write_all(&codec.encode_response(&failure_response));
+            let _ = stream.flush();
+            return;
+        }
+    };
+    // Deadlines count from when the request was read, so time spent waiting in the queue counts
+    let token = match timeout {
+        Some(timeout) => CancellationToken::with_deadline(Instant::now() + timeout),
+        None => CancellationToken::new(),
+    };
+    if let Request::Publish { .. } = request {
+        let state_for_job = Arc::clone(&state);
+        run_as_bulk(&state, move || {
+            process_message(state_for_job, request, token, codec, stream, &peer)
+        })
     } else {
-        eprintln!("Failed to send response to client");
+        process_message(state, request, token, codec, stream, &peer)
+    }
+}
+
+// Connections are handled as interactive jobs, since until the request has been read there is no
+// telling what it is. Requests that turn out to be bulk work, like publishing a document, are put
+// back in the queue behind the interactive ones with `job` finishing them off. If the queue has no
+// room for it, `job` runs straight away instead.
+fn run_as_bulk<F>(state: &Arc<ServerState>, job: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    // Count the request as in flight again before the connection's job stops counting it
+    let guard = InFlightGuard::new(state);
+    let queued = state.pool.try_execute_with_priority(Priority::Bulk, move || {
+        job();
+        drop(guard);
+    });
+    if let Err(QueueFull(job)) = queued {
+        job();
     }
 }
 
+// Wait up to the idle timeout for a new connection to start sending its request, then switch to
+// the read timeout for the rest of it. On success the reader holds at least one byte.
+fn await_request<'a>(
+    state: &ServerState,
+    stream: &'a mut Stream,
+) -> io::Result<BufReader<&'a mut Stream>> {
+    let limits = &state.config.limits;
+    stream.set_write_timeout(limits.write_timeout())?;
+    stream.set_read_timeout(limits.idle_timeout())?;
+    let mut reader = BufReader::new(stream);
+    if reader.fill_buf()?.is_empty() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    reader.get_ref().set_read_timeout(limits.read_timeout())?;
+    Ok(reader)
+}
+
+// Socket timeouts surface as `WouldBlock` on Unix and `TimedOut` on Windows
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+// Log a connection that is being closed without a response because the client went quiet or
+// hung up before sending a request
+fn log_unanswered(peer: &str, e: &io::Error) {
+    if is_timeout(e) {
+        info!("Connection from {} timed out, closing it", peer);
+    } else if e.kind() != io::ErrorKind::UnexpectedEof {
+        warn!("Failed to read request from {}: {}", peer, e);
+    }
+}
+
+// Answer a single HTTP request on a connection accepted by the gateway listener
+fn handle_http_connection(state: Arc<ServerState>, mut stream: Stream, peer: String) {
+    let mut reader = match await_request(&state, &mut stream) {
+        Ok(reader) => reader,
+        Err(e) => return log_unanswered(&peer, &e),
+    };
+    let max_body = state.config.limits.max_document_bytes;
+    let read = HttpRequest::read(&mut reader, max_body);
+    drop(reader);
+
+    match read {
+        // Only publishing changes anything, and it is the one slow request
+        Ok(request) if request.method == "POST" => {
+            let state_for_job = Arc::clone(&state);
+            run_as_bulk(&state, move || {
+                answer_http(&state_for_job, &request, stream, &peer)
+            })
+        }
+        Ok(request) => answer_http(&state, &request, stream, &peer),
+        Err(e) if is_timeout(&e) => log_unanswered(&peer, &e),
+        Err(e) => {
+            warn!("Failed to parse HTTP request from {}: {}", peer, e);
+            let response = HttpResponse::error(400, &e.to_string());
+            send_http(response, stream, "http", &peer, Instant::now());
+        }
+    }
+}
+
+fn answer_http(state: &ServerState, request: &HttpRequest, stream: Stream, peer: &str) {
+    let started = Instant::now();
+    let response = route_http(state, request, peer);
+    let kind = format!("{} {}", request.method, request.path);
+    send_http(response, stream, &kind, peer, started);
+}
+
+fn send_http(response: HttpResponse, mut stream: Stream, kind: &str, peer: &str, started: Instant) {
+    if let Err(e) = response.write_to(&mut stream) {
+        warn!("Failed to send HTTP response to {}: {}", peer, e);
+    }
+    log_request(kind, peer, started.elapsed(), &response.status.to_string());
+}
+
+// Run a request for a client that isn't using the protocol, keeping the slow query log as
+// `process_message` does.
+fn execute_recorded(
+    state: &ServerState,
+    request: Request,
+    token: &CancellationToken,
+    peer: &str,
+) -> Response {
+    let started = Instant::now();
+    let kind = RequestKind::of(&request);
+    let summary = summarize(&request);
+    let response = execute_request(state, request, token);
+    record_if_slow(state, peer, kind, summary, result_size(&response), started);
+    response
+}
+
+/// The body of a `POST /documents` request
+#[derive(Deserialize)]
+struct PublishBody {
+    doc: String,
+}
+
+// Translate a REST call into the equivalent protocol request, run it through `execute_request`,
+// and translate the response back into JSON.
+fn route_http(state: &ServerState, http: &HttpRequest, peer: &str) -> HttpResponse {
+    // HTTP requests don't carry deadlines
+    let token = &CancellationToken::new();
+    let segments: Vec<&str> = http.path.trim_matches('/').split('/').collect();
+    match (http.method.as_str(), segments.as_slice()) {
+        ("POST", ["documents"]) => {
+            let body: PublishBody = match serde_json::from_slice(&http.body) {
+                Ok(body) => body,
+                Err(e) => return HttpResponse::error(400, &format!("invalid body: {}", e)),
+            };
+            match execute_recorded(state, Request::Publish { doc: body.doc }, token, peer) {
+                Response::PublishSuccess(id) => HttpResponse::json(201, &json!({ "id": id })),
+                _ => HttpResponse::error(500, "failed to publish document"),
+            }
+        }
+        ("GET", ["documents", id]) => {
+            let parse_u64 = |name| http.query_param(name).map(str::parse::<u64>).transpose();
+            let (id, offset, len) = match (id.parse::<u64>(), parse_u64("offset"), parse_u64("len"))
+            {
+                (Ok(id), Ok(offset), Ok(len)) => (DocId(id), offset.unwrap_or(0), len),
+                _ => return HttpResponse::error(400, "invalid document id or range"),
+            };
+            match execute_recorded(state, Request::Retrieve { id, offset, len }, token, peer) {
+                Response::RetrieveSuccess(doc) => {
+                    HttpResponse::json(200, &json!({ "id": id, "doc": doc }))
+                }
+                _ => HttpResponse::error(404, "document or range not found"),
+            }
+        }
+        ("GET", ["search"]) => {
+            let word = match http.query_param("q") {
+                Some(word) => word.to_string(),
+                None => return HttpResponse::error(400, "missing query parameter q"),
+            };
+            match execute_recorded(state, Request::Search { word }, token, peer) {
+                Response::SearchSuccess(ids) => HttpResponse::json(200, &json!({ "ids": ids })),
+                _ => HttpResponse::error(500, "search failed"),
+            }
+        }
+        ("GET", ["metrics"]) => HttpResponse::text(200, prometheus_text(&state.stats())),
+        (_, ["documents"]) | (_, ["documents", _]) | (_, ["search"]) | (_, ["metrics"]) => {
+            HttpResponse::error(405, "method not allowed")
+        }
+        _ => HttpResponse::error(404, "no such endpoint"),
+    }
+}
+
+// Tell a client its connection is being shed because the job queue is full. No request has been
+// read yet, so the codec can't be detected and the reply is always in the binary encoding.
+fn reject_overloaded(stream: &mut Stream) {
+    let response = BinaryCodec.encode_response(&Response::Error(ErrorCode::Overloaded));
+    let _ = stream.write_all(&response);
+    let _ = stream.flush();
+    discard_unread(stream);
+}
+
+fn reject_overloaded_http(stream: &mut Stream) {
+    let _ = HttpResponse::error(503, &ErrorCode::Overloaded.to_string()).write_to(stream);
+    discard_unread(stream);
+}
+
+// Closing a socket with unread request bytes makes the OS reset the connection, which can throw
+// away the reply before the client reads it. Finish sending, then throw away whatever has already
+// arrived without waiting for more, since this runs on the accepting thread.
+fn discard_unread(stream: &mut Stream) {
+    let _ = stream.shutdown(Shutdown::Write);
+    if stream.set_nonblocking(true).is_ok() {
+        let _ = io::copy(&mut stream.take(MAX_DISCARD_BYTES), &mut io::sink());
+    }
+}
+
+// Accept connections on `listener` until the server is stopped, handing each one to `handler` on
+// the thread pool. When the server is already handling as many connections as it may, or the
+// pool's queue is full, the connection is answered with `reject` on the accepting thread instead.
+// The listener is polled rather than blocking in `accept`, so that `stop` takes effect within one
+// poll interval even when no clients are connecting.
+fn accept_loop(
+    state: &Arc<ServerState>,
+    listener: Listener,
+    handler: fn(Arc<ServerState>, Stream, String),
+    reject: fn(&mut Stream),
+) {
+    if let Err(e) = listener.set_nonblocking(true) {
+        error!("Failed to make listener non-blocking: {}", e);
+        return;
+    }
+    while !state.is_stopped.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((mut stream, peer)) => {
+                debug!("New connection from: {}", peer);
+                // Some platforms hand out sockets that inherit the listener's non-blocking mode
+                if let Err(e) = stream.set_nonblocking(false) {
+                    warn!("Failed to configure connection from {}: {}", peer, e);
+                    continue;
+                }
+                // Connections beyond the cap are turned away straight away rather than queued
+                // behind ones that may never finish
+                let max_connections = state.config.limits.max_connections;
+                if state.in_flight.load(Ordering::SeqCst) >= max_connections {
+                    state.shed.fetch_add(1, Ordering::Relaxed);
+                    warn!(
+                        "Shedding connection from {}: already handling {} connections",
+                        peer, max_connections
+                    );
+                    let _ = stream.set_write_timeout(state.config.limits.write_timeout());
+                    reject(&mut stream);
+                    continue;
+                }
+                // Keep a handle to reply on in case the job is handed back
+                let mut reply_stream = match stream.try_clone() {
+                    Ok(reply_stream) => reply_stream,
+                    Err(e) => {
+                        warn!("Failed to configure connection from {}: {}", peer, e);
+                        continue;
+                    }
+                };
+                let guard = InFlightGuard::new(state);
+                let job = state.pool.try_execute(move || {
+                    handler(Arc::clone(&guard.0), stream, peer);
+                    drop(guard);
+                });
+                if let Err(QueueFull(job)) = job {
+                    drop(job);
+                    state.shed.fetch_add(1, Ordering::Relaxed);
+                    warn!("Job queue is full, shedding a connection");
+                    let _ = reply_stream.set_write_timeout(state.config.limits.write_timeout());
+                    reject(&mut reply_stream);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+            }
+            Err(e) => {
+                error!("Failed to accept connection: {}", e);
+                // Small sleep to prevent tight loop on error
+                thread::sleep(Duration::from_millis(100));
+            }
+        }
+    }
+}
+
+// Counts a connection as in flight until dropped, which happens when the job handling it finishes
+// or panics, or if the job is thrown away without running
+struct InFlightGuard(Arc<ServerState>);
+impl InFlightGuard {
+    fn new(state: &Arc<ServerState>) -> Self {
+        state.in_flight.fetch_add(1, Ordering::SeqCst);
+        Self(Arc::clone(state))
+    }
+}
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Compare tokens without stopping at the first difference, so response times don't reveal how much
+// of a guessed token was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
 /// A struct that contains the state of the server
 struct ServerState {
+    /// The configuration the server was created with
+    config: ServerConfig,
     /// The database that the server uses to store documents
     database: Database,
     /// The thread pool that the server uses to process requests
     pool: ThreadPool,
     /// A flag that indicates whether the server has been stopped
     is_stopped: AtomicBool,
+    /// The number of accepted connections whose requests haven't been fully handled yet
+    in_flight: AtomicUsize,
+    /// The number of connections turned away because the job queue was full
+    shed: AtomicU64,
+    /// Counters and latencies for the requests handled so far
+    metrics: Metrics,
+    /// The most recent requests that took longer than the slow query threshold
+    slow_queries: SlowQueryLog,
+    /// When the server was created
+    started: Instant,
+    /// Whether the data directory, if any, has been loaded
+    loaded: AtomicBool,
+    /// Whether `run` should wait for in-flight requests once the server is stopped
+    drain_on_stop: AtomicBool,
+    /// Whether the main listener has been bound yet, and to which address
+    listen_state: Mutex<ListenState>,
+    /// Notified when `listen_state` leaves `Starting`
+    listen_ready: Condvar,
+    /// Cancels requests whose clients hang up
+    peers: Arc<PeerWatcher>,
 }
 impl ServerState {
-    fn new() -> Self {
+    fn new(config: ServerConfig) -> Self {
         Self {
-            database: Database::new(),
-            pool: ThreadPool::new(WORKERS),
+            database: Database::with_options(config.buckets, config.analyzer),
+            pool: ThreadPool::builder()
+                .size(config.workers)
+                .max_size(config.max_workers.unwrap_or(config.workers))
+                .idle_timeout(config.worker_idle_timeout())
+                .queue_capacity(config.queue_capacity)
+                .build(),
+            slow_queries: SlowQueryLog::new(config.slow_query_log.clone()),
+            loaded: AtomicBool::new(config.data_dir.is_none()),
+            config,
             is_stopped: AtomicBool::new(false),
+            in_flight: AtomicUsize::new(0),
+            shed: AtomicU64::new(0),
+            metrics: Metrics::new(),
+            started: Instant::now(),
+            drain_on_stop: AtomicBool::new(true),
+            listen_state: Mutex::new(ListenState::Starting),
+            listen_ready: Condvar::new(),
+            peers: PeerWatcher::start(),
         }
     }
+
+    fn is_ready(&self) -> bool {
+        self.loaded.load(Ordering::SeqCst) && !self.is_stopped.load(Ordering::SeqCst)
+    }
+
+    fn is_admin(&self, token: Option<&str>) -> bool {
+        match (&self.config.admin_token, token) {
+            (Some(expected), Some(token)) => {
+                constant_time_eq(expected.as_bytes(), token.as_bytes())
+            }
+            _ => false,
+        }
+    }
+
+    // Stop accepting connections, and tell `run` whether to wait for the ones in flight
+    fn stop(&self, drain: bool) {
+        self.drain_on_stop.store(drain, Ordering::SeqCst);
+        self.is_stopped.store(true, Ordering::SeqCst);
+    }
+
+    fn stats(&self) -> Stats {
+        Stats {
+            documents: self.database.document_count() as u64,
+            vocabulary: self.database.vocabulary_size() as u64,
+            total_tokens: self.database.token_count(),
+            queue_depth: self.pool.queued() as u64,
+            active_workers: self.pool.active() as u64,
+            shed: self.shed.load(Ordering::Relaxed),
+            panics: self.pool.panics(),
+            requests: self.metrics.snapshot(),
+            workers: self
+                .pool
+                .worker_stats()
+                .into_iter()
+                .map(|worker| WorkerStats {
+                    id: worker.id as u64,
+                    jobs: worker.jobs,
+                    busy_micros: worker.busy.as_micros().try_into().unwrap_or(u64::MAX),
+                    panics: worker.panics,
+                })
+                .collect(),
+        }
+    }
+
+    fn set_listen_state(&self, listen_state: ListenState) {
+        *self.listen_state.lock().unwrap() = listen_state;
+        self.listen_ready.notify_all();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ListenState {
+    Starting,
+    Listening(SocketAddr),
+    Failed,
 }
 
 pub struct Server {
     state: Arc<ServerState>,
+    /// How long `run` waits for in-flight requests to finish after the server is stopped
+    shutdown_timeout: Duration,
+}
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 impl Server {
     // TODO:
     // Create a new server by using the `ServerState::new` function
     pub fn new() -> Self {
+        Self::with_config(ServerConfig::default())
+    }
+
+    /// Create a new server with the given configuration. The configuration should come from
+    /// `ServerConfigBuilder::build`, which validates it.
+    pub fn with_config(config: ServerConfig) -> Self {
         Self {
-            state: Arc::new(ServerState::new()),
+            shutdown_timeout: config.shutdown_timeout(),
+            state: Arc::new(ServerState::new(config)),
+        }
+    }
+
+    /// Publish every file in the configured data directory, in file name order, so that the
+    /// documents get predictable IDs. Returns the number of documents published.
+    pub fn load_data_dir(&self) -> io::Result<usize> {
+        let dir = match &self.state.config.data_dir {
+            Some(dir) => dir,
+            None => return Ok(0),
+        };
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                paths.push(entry.path());
+            }
+        }
+        paths.sort();
+        for path in &paths {
+            let doc = std::fs::read_to_string(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+            self.state.database.publish_on(doc, &self.state.pool);
         }
+        info!("Loaded {} documents from {}", paths.len(), dir.display());
+        self.state.loaded.store(true, Ordering::SeqCst);
+        Ok(paths.len())
+    }
+
+    /// Run the server as configured: start the HTTP gateway and the Unix socket listener if they
+    /// are configured, and serve requests on the configured address until stopped. The data
+    /// directory is loaded in the background meanwhile, and the server reports itself ready once
+    /// it has been; if loading fails the server stops and the error is returned.
+    pub fn serve(&self) -> io::Result<()> {
+        let config = &self.state.config;
+        thread::scope(|scope| {
+            let loader = scope.spawn(|| {
+                let loaded = self.load_data_dir();
+                if let Err(e) = &loaded {
+                    error!("Failed to load the data directory: {}", e);
+                    self.stop();
+                }
+                loaded
+            });
+
+            let listeners = self.start_listeners();
+            if listeners.is_err() {
+                self.stop();
+            } else {
+                self.run_on((config.bind, config.port));
+            }
+            loader.join().expect("data directory loader panicked")?;
+            listeners
+        })?;
+
+        if *self.state.listen_state.lock().unwrap() == ListenState::Failed {
+            return Err(io::Error::other(format!(
+                "failed to listen on {}",
+                SocketAddr::new(config.bind, config.port)
+            )));
+        }
+        Ok(())
+    }
+
+    // Start the optional HTTP gateway and Unix socket listener
+    fn start_listeners(&self) -> io::Result<()> {
+        let config = &self.state.config;
+        if let Some(http_port) = config.http_port {
+            self.start_http((config.bind, http_port))?;
+        }
+        #[cfg(unix)]
+        if let Some(path) = &config.unix_socket {
+            self.start_unix(path)?;
+        }
+        Ok(())
+    }
+
+    /// Set how long `run` waits for in-flight requests to finish once the server is stopped
+    /// before returning anyway
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
     }
 
     // TODO:
@@ -93,56 +876,91 @@ impl Server {
     // While looping to accept connections, you should also check the `is_stopped` flag in the
     // `ServerState` to see if the server has been stopped. If it has, you should break out of the
     // loop and return.
-    fn listen(&self, port: u16) {
-        // Bind to the specified port
-        let listener = match TcpListener::bind(("127.0.0.1", port)) {
-            Ok(listener) => listener,
+    fn listen<A: ToSocketAddrs + fmt::Debug>(&self, addr: A) {
+        // Bind to the specified address, and report the address we actually got, which differs
+        // from the requested one when asking for port 0
+        let listener = match TcpListener::bind(&addr).and_then(|l| Ok((l.local_addr()?, l))) {
+            Ok((local_addr, listener)) => {
+                self.state
+                    .set_listen_state(ListenState::Listening(local_addr));
+                info!("Server listening on {}", local_addr);
+                listener
+            }
             Err(e) => {
-                eprintln!("Failed to bind to port {}: {}", port, e);
+                error!("Failed to bind to {:?}: {}", addr, e);
+                self.state.set_listen_state(ListenState::Failed);
+                // The other listeners share the pool, which is about to be shut down
+                self.state.stop(true);
                 return;
             }
         };
 
-        println!("Server listening on port {}", port);
-
-        while !self.state.is_stopped.load(Ordering::SeqCst) {
-            match listener.accept() {
-                Ok((stream, addr)) => {
-                    println!("New connection from: {}", addr);
-                    let state = Arc::clone(&self.state);
-
-                    self.state.pool.execute(move || {
-                        let mut stream = stream;
-                        // Use the Request::from_bytes to deserialize the request
-                        match Request::from_bytes(&stream) {
-                            Some(request) => {
-                                process_message(state, request, stream);
-                            }
-                            None => {
-                                eprintln!("Failed to parse request from {}", addr);
-                                // Send failure response in case of invalid request
-                                let failure_response = Response::Failure;
-                                let _ = stream.write_all(&failure_response.to_bytes());
-                                let _ = stream.flush();
-                            }
-                        }
-                    });
-                }
-                Err(e) => {
-                    eprintln!("Failed to accept connection: {}", e);
-                    // Small sleep to prevent tight loop on error
-                    thread::sleep(std::time::Duration::from_millis(100));
-                }
-            }
-        }
+        accept_loop(
+            &self.state,
+            Listener::Tcp(listener),
+            handle_connection,
+            reject_overloaded,
+        );
+    }
+
+    /// Start the HTTP/REST gateway on `addr`, alongside the binary protocol. The gateway shares the
+    /// server's database and thread pool, and stops when the server does. Returns once the address
+    /// is bound, with the bound address and a handle to the thread accepting connections.
+    ///
+    /// Endpoints, all answering with JSON:
+    /// - `POST /documents` with a body of `{"doc": "..."}` publishes a document
+    /// - `GET /documents/{id}` retrieves a document, optionally with `?offset=` and `&len=`
+    /// - `GET /search?q=word` searches for a word
+    pub fn start_http<A: ToSocketAddrs>(
+        &self,
+        addr: A,
+    ) -> io::Result<(SocketAddr, JoinHandle<()>)> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        info!("HTTP gateway listening on {}", local_addr);
+        let state = Arc::clone(&self.state);
+        let handle = thread::spawn(move || {
+            accept_loop(
+                &state,
+                Listener::Tcp(listener),
+                handle_http_connection,
+                reject_overloaded_http,
+            )
+        });
+        Ok((local_addr, handle))
+    }
+
+    /// Also accept requests on a Unix domain socket at `path`, alongside the TCP listener. Clients
+    /// connect to it with a `unix:/path` address, and access can be restricted with the file's
+    /// permissions. Returns once the socket is bound, with a handle to the thread accepting
+    /// connections. The socket file is removed when the server stops.
+    #[cfg(unix)]
+    pub fn start_unix<P: AsRef<Path>>(&self, path: P) -> io::Result<JoinHandle<()>> {
+        let path = path.as_ref().to_path_buf();
+        let listener = Listener::bind_unix(&path)?;
+        info!("Server listening on unix:{}", path.display());
+        let state = Arc::clone(&self.state);
+        Ok(thread::spawn(move || {
+            accept_loop(&state, listener, handle_connection, reject_overloaded);
+            let _ = std::fs::remove_file(&path);
+        }))
     }
 
-    // This function has already been partially completed for you
+    /// Serve requests on `port` of the loopback interface. See `run_on`.
     pub fn run(&self, port: u16) {
+        self.run_on(("127.0.0.1", port));
+    }
+
+    /// Serve requests on `addr`, which may be any IPv4 or IPv6 address and may use port 0 to have
+    /// the OS pick a free port (see `local_addr`), until the server is stopped, either by `stop`
+    /// or by Ctrl-C. Once stopped, no new connections are accepted, and requests already being
+    /// handled are given up to the shutdown timeout to finish before this returns. Workers still
+    /// busy after that are left to finish in the background.
+    pub fn run_on<A: ToSocketAddrs + fmt::Debug>(&self, addr: A) {
         // Set up a signal handler to stop the server when Ctrl-C is pressed
         let state = Arc::clone(&self.state);
         match ctrlc::try_set_handler(move || {
-            println!("Stopping server...");
+            info!("Stopping server...");
             state.is_stopped.store(true, Ordering::SeqCst);
         }) {
             Ok(_) => {}
@@ -152,13 +970,88 @@ impl Server {
             }
         }
 
-        // TODO: Call the listen function and then loop (doing nothing) until the server has been stopped
-        self.listen(port);
-        while !self.state.is_stopped.load(Ordering::SeqCst) {
-            thread::sleep(std::time::Duration::from_millis(100));
+        self.listen(addr);
+        self.drain();
+        self.shut_down_pool();
+    }
+
+    /// The number of connections turned away so far because the job queue was full
+    pub fn shed_count(&self) -> u64 {
+        self.state.shed.load(Ordering::Relaxed)
+    }
+
+    /// A snapshot of the server's statistics, as returned for a `Stats` request
+    pub fn stats(&self) -> Stats {
+        self.state.stats()
+    }
+
+    /// The address the server is listening on, once `run` has bound it
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match *self.state.listen_state.lock().unwrap() {
+            ListenState::Listening(addr) => Some(addr),
+            _ => None,
+        }
+    }
+
+    /// Block until `run` has bound its address, and return it. Returns `None` if binding failed
+    /// or didn't happen within `timeout`.
+    pub fn wait_for_addr(&self, timeout: Duration) -> Option<SocketAddr> {
+        let listen_state = self.state.listen_state.lock().unwrap();
+        let (listen_state, _) = self
+            .state
+            .listen_ready
+            .wait_timeout_while(listen_state, timeout, |s| *s == ListenState::Starting)
+            .unwrap();
+        match *listen_state {
+            ListenState::Listening(addr) => Some(addr),
+            _ => None,
         }
     }
+
+    /// Stop accepting connections. `run` returns once in-flight requests have finished or the
+    /// shutdown timeout has passed.
     pub fn stop(&self) {
-        self.state.is_stopped.store(true, Ordering::SeqCst);
+        self.state.stop(true);
+    }
+
+    // Stop the workers once the listener has closed. A server stopped without draining throws
+    // away the connections still waiting for a worker. Either way, workers still busy are left
+    // behind rather than holding up the exit.
+    fn shut_down_pool(&self) {
+        let pool = &self.state.pool;
+        if !self.state.drain_on_stop.load(Ordering::SeqCst) {
+            let discarded = pool.shutdown_now();
+            if discarded > 0 {
+                warn!("Dropped {} connection(s) waiting for a worker", discarded);
+            }
+        }
+        // Requests still running have already had the whole shutdown timeout, so only give idle
+        // workers a moment to notice the pool closing
+        if let Err(e) = pool.shutdown(WORKER_EXIT_GRACE) {
+            warn!("Leaving {}", e);
+        }
+    }
+
+    // Wait for in-flight requests to finish, up to the shutdown timeout, unless the server was
+    // stopped without draining
+    fn drain(&self) {
+        if !self.state.drain_on_stop.load(Ordering::SeqCst) {
+            return;
+        }
+        let deadline = Instant::now() + self.shutdown_timeout;
+        loop {
+            let in_flight = self.state.in_flight.load(Ordering::SeqCst);
+            if in_flight == 0 {
+                return;
+            }
+            if Instant::now() >= deadline {
+                warn!(
+                    "Shutdown timeout reached with {} request(s) still in flight",
+                    in_flight
+                );
+                return;
+            }
+            thread::sleep(ACCEPT_POLL_INTERVAL);
+        }
     }
 }
@@ -0,0 +1,67 @@
+// Requests that take longer than a threshold are kept in a small ring buffer, so the most recent
+// ones can be listed with an admin request, and optionally appended to a file as JSON lines.
+// Slow requests are rare, so the file is opened for each one rather than held open; that also
+// lets it be rotated without restarting the server.
+
+use crate::config::SlowQueryLogConfig;
+use crate::message::SlowQuery;
+use log::warn;
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub(crate) struct SlowQueryLog {
+    config: SlowQueryLogConfig,
+    recent: Mutex<VecDeque<SlowQuery>>,
+}
+impl SlowQueryLog {
+    pub fn new(config: SlowQueryLogConfig) -> Self {
+        Self {
+            recent: Mutex::new(VecDeque::with_capacity(config.keep.min(1024))),
+            config,
+        }
+    }
+
+    /// Whether a request that took `elapsed` should be recorded
+    pub fn is_slow(&self, elapsed: Duration) -> bool {
+        elapsed >= self.config.threshold()
+    }
+
+    pub fn record(&self, query: SlowQuery) {
+        warn!(
+            kind = query.kind.as_str(),
+            peer = query.peer.as_str(),
+            duration_us = query.duration_micros,
+            result_size = query.result_size;
+            "Slow request: {}", query.summary
+        );
+
+        if let Some(path) = &self.config.path {
+            let line = serde_json::to_string(&query).expect("slow queries always serialize");
+            let written = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", line));
+            if let Err(e) = written {
+                warn!("Failed to write to slow query log {}: {}", path.display(), e);
+            }
+        }
+
+        if self.config.keep > 0 {
+            let mut recent = self.recent.lock().unwrap();
+            if recent.len() == self.config.keep {
+                recent.pop_front();
+            }
+            recent.push_back(query);
+        }
+    }
+
+    /// Up to `limit` of the most recent slow queries, newest first
+    pub fn recent(&self, limit: usize) -> Vec<SlowQuery> {
+        let recent = self.recent.lock().unwrap();
+        recent.iter().rev().take(limit).cloned().collect()
+    }
+}
@@ -1,3 +1,7 @@
+// Some of the original tests predate the lints the build is checked with
+#![allow(unused_variables, clippy::unnecessary_cast, clippy::assertions_on_constants)]
+#![allow(clippy::empty_loop, clippy::clone_on_copy)]
+
 use quickcheck::quickcheck;
 const THREADS: usize = 16;
 
@@ -140,6 +144,441 @@ mod test_pool {
         drop(pool);
         assert_eq!(*counter.lock().unwrap(), 8);
     }
+
+    #[test]
+    fn test_try_execute_reports_full_queue_5() {
+        let pool = ThreadPool::with_queue_capacity(1, 2);
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+
+        // Occupy the only worker, then fill the queue behind it
+        pool.execute(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        started_rx.recv().unwrap();
+        let counter = Arc::new(Mutex::new(0));
+        for _ in 0..2 {
+            let counter = Arc::clone(&counter);
+            assert!(pool
+                .try_execute(move || *counter.lock().unwrap() += 1)
+                .is_ok());
+        }
+        assert_eq!(pool.queued(), 2);
+        assert!(pool.try_execute(|| ()).is_err());
+
+        // Once the worker is free again the queued jobs all run
+        drop(release_tx);
+        drop(pool);
+        assert_eq!(*counter.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_spawn_returns_results_5() {
+        use std::time::Duration;
+        let pool = ThreadPool::new(2);
+
+        // Results come back from whichever worker ran the job
+        let handles = (0..8u64).map(|n| pool.spawn(move || n * n)).collect::<Vec<_>>();
+        let squares = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(squares, vec![0, 1, 4, 9, 16, 25, 36, 49]);
+
+        // A panic is handed to whoever joins
+        let panicked = pool.spawn(|| -> u32 { panic!("bad job") });
+        let payload = panicked.join().unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"bad job"));
+
+        // A job that hasn't finished gives its handle back
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let slow = pool.spawn(move || {
+            let _ = release_rx.recv();
+            "done"
+        });
+        let slow = slow.join_timeout(Duration::from_millis(50)).unwrap_err();
+        drop(release_tx);
+        let result = slow.join_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(result.unwrap(), "done");
+
+        // Both workers survived the panic
+        assert_eq!(pool.spawn(|| 1).join().unwrap() + pool.spawn(|| 2).join().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_panicking_jobs_keep_workers_5() {
+        let pool = ThreadPool::new(2);
+        for _ in 0..4 {
+            pool.execute(|| panic!("bad request"));
+        }
+
+        // Both workers are still there to run jobs that need two threads at once
+        let (tx, rx) = std::sync::mpsc::channel();
+        let barrier = Arc::new(std::sync::Barrier::new(2));
+        for _ in 0..2 {
+            let (tx, barrier) = (tx.clone(), Arc::clone(&barrier));
+            pool.execute(move || {
+                barrier.wait();
+                tx.send(()).unwrap();
+            });
+        }
+        for _ in 0..2 {
+            rx.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+        }
+        assert_eq!(pool.panics(), 4);
+
+        // Dropping the pool joins the workers without panicking
+        drop(pool);
+    }
+
+    #[test]
+    fn test_worker_threads_5() {
+        let pool = ThreadPool::builder()
+            .size(2)
+            .thread_name("indexer")
+            .stack_size(256 * 1024)
+            .build();
+        let name = pool.spawn(|| std::thread::current().name().map(str::to_string));
+        let name = name.join().unwrap().unwrap();
+        assert!(name == "indexer-0" || name == "indexer-1", "{}", name);
+
+        for i in 0..6 {
+            pool.execute(move || assert!(i % 3 != 0));
+        }
+        // The counters are updated just after each job returns
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while pool.worker_stats().iter().map(|w| w.jobs).sum::<u64>() < 7 {
+            assert!(std::time::Instant::now() < deadline);
+            std::thread::yield_now();
+        }
+        let stats = pool.worker_stats();
+        assert_eq!(stats.iter().map(|w| w.id).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(stats.iter().map(|w| w.panics).sum::<u64>(), 2);
+
+        let default = ThreadPool::new(1);
+        let name = default.spawn(|| std::thread::current().name().map(str::to_string));
+        assert_eq!(name.join().unwrap().as_deref(), Some("ngram-worker-0"));
+    }
+
+    #[test]
+    fn test_many_small_jobs_5() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let pool = Arc::new(ThreadPool::with_queue_capacity(4, 8));
+        let counter = Arc::new(AtomicUsize::new(0));
+
+        // Several threads submitting at once into a small queue, so they often wait for room
+        let submitters = (0..4)
+            .map(|_| {
+                let (pool, counter) = (Arc::clone(&pool), Arc::clone(&counter));
+                std::thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        let counter = Arc::clone(&counter);
+                        pool.execute(move || {
+                            counter.fetch_add(1, Ordering::Relaxed);
+                        });
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for submitter in submitters {
+            submitter.join().unwrap();
+        }
+        drop(Arc::into_inner(pool));
+        assert_eq!(counter.load(Ordering::Relaxed), 40_000);
+    }
+
+    #[test]
+    fn test_jobs_behind_a_busy_worker_are_stolen_5() {
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+        let pool = ThreadPool::new(2);
+        let (blocked, done) = (Arc::new(AtomicBool::new(false)), Arc::new(AtomicUsize::new(0)));
+
+        // Whichever job runs first holds up its worker until every other job has run, so jobs
+        // that worker had already taken into its own deque must be taken by the other one
+        for _ in 0..1000 {
+            let (blocked, done) = (Arc::clone(&blocked), Arc::clone(&done));
+            pool.execute(move || {
+                if !blocked.swap(true, Ordering::SeqCst) {
+                    let started = std::time::Instant::now();
+                    while done.load(Ordering::SeqCst) < 999 {
+                        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+                        std::thread::yield_now();
+                    }
+                }
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        drop(pool);
+        assert_eq!(done.load(Ordering::SeqCst), 1000);
+    }
+
+    #[test]
+    fn test_priorities_5() {
+        let pool = ThreadPool::new(1);
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        pool.execute(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        started_rx.recv().unwrap();
+
+        // Queue bulk jobs first, then more interactive jobs than may run in a row
+        let order = Arc::new(Mutex::new(String::new()));
+        for (priority, name) in [(Priority::Bulk, 'B'); 3]
+            .into_iter()
+            .chain([(Priority::Interactive, 'I'); 6])
+        {
+            let order = Arc::clone(&order);
+            pool.execute_with_priority(priority, move || order.lock().unwrap().push(name));
+        }
+        drop(release_tx);
+        drop(pool);
+
+        // Interactive jobs go first, but bulk jobs still get a turn while they are waiting
+        assert_eq!(*order.lock().unwrap(), "IIIIBIIBB");
+    }
+
+    #[test]
+    fn test_scope_borrows_5() {
+        let pool = ThreadPool::new(3);
+        let numbers = (1..=100).collect::<Vec<u64>>();
+        let mut sums = vec![0; 4];
+
+        // Jobs borrow `numbers` and write into `sums`, and are all done when `scope` returns
+        let total = pool.scope(|scope| {
+            for (chunk, sum) in numbers.chunks(25).zip(sums.iter_mut()) {
+                scope.spawn(move || {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                    *sum = chunk.iter().sum();
+                });
+            }
+            numbers.len()
+        });
+        assert_eq!(total, 100);
+        assert_eq!(sums, vec![325, 950, 1575, 2200]);
+
+        // A panicking job is carried on once the others have finished
+        let finished = std::sync::atomic::AtomicBool::new(false);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            pool.scope(|scope| {
+                scope.spawn(|| panic!("bad job"));
+                scope.spawn(|| {
+                    std::thread::sleep(std::time::Duration::from_millis(50));
+                    finished.store(true, std::sync::atomic::Ordering::SeqCst);
+                });
+            })
+        }));
+        assert!(result.is_err());
+        assert!(finished.load(std::sync::atomic::Ordering::SeqCst));
+
+        // Scoped jobs can be queued behind interactive ones
+        let pool = ThreadPool::new(1);
+        let order = Mutex::new(String::new());
+        pool.scope(|scope| {
+            let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+            scope.spawn(move || {
+                let _ = release_rx.recv();
+            });
+            scope.spawn_with_priority(Priority::Bulk, || order.lock().unwrap().push('B'));
+            scope.spawn(|| order.lock().unwrap().push('I'));
+            drop(release_tx);
+        });
+        assert_eq!(*order.lock().unwrap(), "IB");
+    }
+
+    #[test]
+    fn test_shutdown_5() {
+        use std::time::{Duration, Instant};
+
+        // Queued jobs are finished before the deadline
+        let pool = ThreadPool::new(2);
+        let counter = Arc::new(Mutex::new(0));
+        for _ in 0..10 {
+            let counter = Arc::clone(&counter);
+            pool.execute(move || *counter.lock().unwrap() += 1);
+        }
+        assert_eq!(pool.shutdown(Duration::from_secs(5)), Ok(()));
+        assert_eq!(*counter.lock().unwrap(), 10);
+        assert!(pool.try_execute(|| ()).is_err());
+
+        // A job that doesn't finish is reported rather than waited for
+        let pool = ThreadPool::new(2);
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        pool.execute(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        started_rx.recv().unwrap();
+        let start = Instant::now();
+        let unfinished = pool.shutdown(Duration::from_millis(100)).unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(unfinished.unfinished.len(), 1);
+        // Dropping the pool doesn't wait for it either
+        drop(pool);
+        drop(release_tx);
+    }
+
+    #[test]
+    fn test_timers_5() {
+        use std::sync::mpsc;
+        use std::time::{Duration, Instant};
+        let pool = ThreadPool::new(2);
+
+        // Delayed jobs run in the order they fall due, not the order they were scheduled in
+        let (tx, rx) = mpsc::channel();
+        let start = Instant::now();
+        for (delay, name) in [(60, "second"), (20, "first")] {
+            let tx = tx.clone();
+            pool.execute_after(Duration::from_millis(delay), move || tx.send(name).unwrap());
+        }
+        let cancelled = pool.execute_after(Duration::from_millis(10), move || {
+            tx.send("cancelled").unwrap()
+        });
+        cancelled.cancel();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok("first"));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok("second"));
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+
+        // Periodic jobs carry on after a panic and stop once cancelled
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let runs = std::sync::atomic::AtomicUsize::new(0);
+        let ticker = pool.execute_every(Duration::from_millis(5), move || {
+            let run = runs.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let _ = tx.lock().unwrap().send(run);
+            assert!(run != 1);
+        });
+        for expected in 0..4 {
+            assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(expected));
+        }
+        ticker.cancel();
+        assert!(ticker.is_cancelled());
+        // At most a run that was already queued can still arrive
+        while rx.recv_timeout(Duration::from_millis(50)).is_ok() {}
+        assert_eq!(pool.panics(), 1);
+
+        // Shutting down cancels what hasn't fallen due
+        let (tx, rx) = mpsc::channel();
+        pool.execute_after(Duration::from_millis(20), move || tx.send(()).unwrap());
+        assert_eq!(pool.shutdown(Duration::from_secs(5)), Ok(()));
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+        let late = pool.execute_after(Duration::ZERO, || {});
+        assert!(late.is_cancelled());
+    }
+
+    #[test]
+    fn test_due_timers_wake_idle_workers_5() {
+        use std::time::Duration;
+        let pool = ThreadPool::new(2);
+        // Let both workers go to sleep with no timers pending
+        std::thread::sleep(Duration::from_millis(50));
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let slow_tx = tx.clone();
+        pool.execute_after(Duration::from_millis(50), move || {
+            std::thread::sleep(Duration::from_secs(1));
+            slow_tx.send("slow").unwrap();
+        });
+        pool.execute_after(Duration::from_millis(50), move || tx.send("fast").unwrap());
+
+        // The second job is picked up by the other worker rather than waiting behind the first
+        assert_eq!(rx.recv_timeout(Duration::from_millis(500)), Ok("fast"));
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok("slow"));
+    }
+
+    #[test]
+    fn test_shutdown_now_5() {
+        use std::time::Duration;
+        let pool = ThreadPool::new(1);
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        pool.execute(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        started_rx.recv().unwrap();
+
+        let counter = Arc::new(Mutex::new(0));
+        for priority in [Priority::Interactive, Priority::Bulk, Priority::Interactive] {
+            let counter = Arc::clone(&counter);
+            pool.execute_with_priority(priority, move || *counter.lock().unwrap() += 1);
+        }
+        let handle = pool.spawn(|| 1);
+
+        // Queued jobs are thrown away and the running one is left to finish
+        assert_eq!(pool.shutdown_now(), 4);
+        assert!(handle.join().is_err());
+        drop(release_tx);
+        assert_eq!(pool.shutdown(Duration::from_secs(5)), Ok(()));
+        assert_eq!(*counter.lock().unwrap(), 0);
+
+        // A scope still completes its jobs on a pool that has been shut down
+        let mut done = false;
+        pool.scope(|scope| scope.spawn(|| done = true));
+        assert!(done);
+    }
+
+    // Run `n` jobs that can only finish if they all run at the same time
+    fn run_together(pool: &ThreadPool, n: usize) {
+        let barrier = Arc::new(std::sync::Barrier::new(n));
+        let handles = (0..n)
+            .map(|_| {
+                let barrier = Arc::clone(&barrier);
+                pool.spawn(move || {
+                    barrier.wait();
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle
+                .join_timeout(std::time::Duration::from_secs(5))
+                .expect("jobs did not run concurrently")
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_resize_5() {
+        let pool = ThreadPool::new(1);
+        pool.resize(3);
+        assert_eq!(pool.size(), 3);
+        run_together(&pool, 3);
+
+        // A worker that is removed finishes its job first
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let busy = pool.spawn(move || release_rx.recv().is_err());
+        pool.resize(1);
+        assert_eq!(pool.size(), 1);
+        drop(release_tx);
+        assert!(busy.join().unwrap());
+        assert_eq!(pool.spawn(|| 7).join().unwrap(), 7);
+    }
+
+    #[test]
+    fn test_autoscale_5() {
+        use std::time::{Duration, Instant};
+        let pool = ThreadPool::builder()
+            .size(1)
+            .max_size(3)
+            .idle_timeout(Duration::from_millis(50))
+            .build();
+
+        // Waiting jobs bring in more workers, up to the maximum
+        run_together(&pool, 3);
+        assert_eq!(pool.size(), 3);
+
+        // Once idle, the pool shrinks back to its starting size
+        let start = Instant::now();
+        while pool.size() > 1 {
+            assert!(start.elapsed() < Duration::from_secs(5), "pool did not shrink");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        run_together(&pool, 1);
+    }
 }
 
 // ============================ SERIALIZE ============================
@@ -148,10 +587,16 @@ mod test_serialize {
     use ngram::message::*;
     #[test]
     fn test_round_trip_request_5() {
-        fn round_trip_request(s: String, n: usize) {
+        fn round_trip_request(s: String, n: u64, offset: u64, len: Option<u64>, chunk: u32) {
+            let n = DocId(n);
             let pub_request = Request::Publish { doc: s.clone() };
             let search_request = Request::Search { word: s };
-            let retrieve_request = Request::Retrieve { id: n };
+            let retrieve_request = Request::Retrieve { id: n, offset, len };
+            let stream_request = Request::RetrieveStream {
+                id: n,
+                offset,
+                chunk_size: chunk,
+            };
             assert_eq!(
                 Request::from_bytes(&pub_request.to_bytes()[..]).unwrap(),
                 pub_request
@@ -164,16 +609,22 @@ mod test_serialize {
                 Request::from_bytes(&retrieve_request.to_bytes()[..]).unwrap(),
                 retrieve_request
             );
+            assert_eq!(
+                Request::from_bytes(&stream_request.to_bytes()[..]).unwrap(),
+                stream_request
+            );
         }
-        quickcheck(round_trip_request as fn(String, usize));
+        quickcheck(round_trip_request as fn(String, u64, u64, Option<u64>, u32));
     }
 
     #[test]
     fn test_round_trip_response_5() {
-        fn round_trip_response(s: String, n: usize) {
+        fn round_trip_response(s: String, n: u64) {
+            let n = DocId(n);
             let pub_response = Response::PublishSuccess(n);
             let search_response = Response::SearchSuccess(vec![n]);
             let retrieve_response = Response::RetrieveSuccess(s.clone());
+            let chunk_response = Response::RetrieveChunk(s.clone());
             assert_eq!(
                 Response::from_bytes(&pub_response.to_bytes()[..]).unwrap(),
                 pub_response
@@ -186,8 +637,438 @@ mod test_serialize {
                 Response::from_bytes(&retrieve_response.to_bytes()[..]).unwrap(),
                 retrieve_response
             );
+            assert_eq!(
+                Response::from_bytes(&chunk_response.to_bytes()[..]).unwrap(),
+                chunk_response
+            );
+            assert_eq!(
+                Response::from_bytes(&Response::RetrieveEnd.to_bytes()[..]).unwrap(),
+                Response::RetrieveEnd
+            );
+        }
+        quickcheck(round_trip_response as fn(String, u64));
+    }
+}
+
+// ============================ CODECS ============================
+mod test_codec {
+    use super::*;
+    use ngram::message::*;
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    fn round_trip(codec: &dyn Codec, s: String, n: u64) {
+        let requests = vec![
+            Request::Publish { doc: s.clone() },
+            Request::Search { word: s.clone() },
+            Request::retrieve(DocId(n)),
+        ];
+        let requests = requests.into_iter().chain([
+            Request::Stats,
+            Request::Admin {
+                token: None,
+                command: AdminCommand::SlowQueries { limit: n as u32 },
+            },
+            Request::Admin {
+                token: Some(s.clone()),
+                command: AdminCommand::Shutdown { graceful: n.is_multiple_of(2) },
+            },
+            Request::Admin {
+                token: None,
+                command: AdminCommand::Health,
+            },
+            Request::Admin {
+                token: Some(String::new()),
+                command: AdminCommand::Ready,
+            },
+            Request::Admin {
+                token: Some(s.clone()),
+                command: AdminCommand::Flush,
+            },
+            Request::Search { word: s.clone() }.with_timeout(Duration::from_millis(n)),
+        ]);
+        for request in requests {
+            let bytes = codec.encode_request(&request);
+            assert_eq!(codec.decode_request(&mut Cursor::new(bytes)).unwrap(), request);
+        }
+        let responses = vec![
+            Response::PublishSuccess(DocId(n)),
+            Response::SearchSuccess(vec![DocId(n)]),
+            Response::RetrieveSuccess(s.clone()),
+            Response::Failure,
+            Response::Error(ErrorCode::Overloaded),
+            Response::Error(ErrorCode::Unauthorized),
+            Response::Error(ErrorCode::Timeout),
+            Response::Ok,
+            Response::Health(Health {
+                uptime_ms: n,
+                in_flight: n / 2,
+            }),
+            Response::Ready(n.is_multiple_of(2)),
+            Response::Stats(Stats {
+                documents: n,
+                shed: n / 2,
+                panics: n / 4,
+                requests: vec![RequestStats {
+                    kind: "search".into(),
+                    count: n,
+                    failures: 1,
+                    latency_sum_micros: n / 3,
+                    latency_buckets: vec![n, 0, 2],
+                }],
+                workers: vec![WorkerStats {
+                    id: n % 16,
+                    jobs: n,
+                    busy_micros: n / 5,
+                    panics: 1,
+                }],
+                ..Stats::default()
+            }),
+            Response::SlowQueries(vec![SlowQuery {
+                finished_at_ms: n,
+                peer: "127.0.0.1:1234".into(),
+                kind: "search".into(),
+                summary: s.clone(),
+                result_size: n / 2,
+                duration_micros: 7,
+            }]),
+        ];
+        for response in responses {
+            let bytes = codec.encode_response(&response);
+            assert_eq!(codec.decode_response(&mut Cursor::new(bytes)).unwrap(), response);
+        }
+    }
+
+    #[test]
+    fn test_round_trip_binary_5() {
+        fn round_trip_binary(s: String, n: u64) {
+            round_trip(&BinaryCodec, s, n);
+        }
+        quickcheck(round_trip_binary as fn(String, u64));
+    }
+
+    #[test]
+    fn test_round_trip_json_5() {
+        fn round_trip_json(s: String, n: u64) {
+            round_trip(&JsonCodec, s, n);
+        }
+        quickcheck(round_trip_json as fn(String, u64));
+    }
+
+    #[test]
+    fn test_detect_codec_5() {
+        let json = JsonCodec.encode_request(&Request::Search { word: "a".into() });
+        assert_eq!(detect_codec(json[0]).name(), "json");
+        let binary = BinaryCodec.encode_request(&Request::Search { word: "a".into() });
+        assert_eq!(detect_codec(binary[0]).name(), "binary");
+    }
+
+    #[test]
+    fn test_decode_errors_5() {
+        assert!(matches!(
+            Request::from_bytes(&[42][..]),
+            Err(DecodeError::UnknownTag(42))
+        ));
+        assert!(matches!(
+            Request::from_bytes(&[1, 0, 0, 0, 2, 0xff, 0xfe][..]),
+            Err(DecodeError::InvalidUtf8)
+        ));
+        assert!(matches!(
+            Request::from_bytes(&[1, 0, 0, 0, 9, b'a'][..]),
+            Err(DecodeError::Io(_))
+        ));
+        assert!(matches!(
+            JsonCodec.decode_request(&mut Cursor::new("{\"Nope\":{}}\n")),
+            Err(DecodeError::Json(_))
+        ));
+    }
+
+    #[test]
+    fn test_crc32_check_value_5() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn test_corrupted_frame_is_rejected_5() {
+        fn corrupted_frame_is_rejected(doc: String, index: usize, flip: u8) {
+            if doc.is_empty() || flip == 0 {
+                return;
+            }
+            // The frame is a type byte, an 8 byte length, the text and a 4 byte checksum. Only
+            // corrupt the text, so the frame still parses and the checksum has to catch it.
+            let mut bytes = Response::RetrieveSuccess(doc.clone()).to_bytes();
+            bytes[9 + index % doc.len()] ^= flip;
+            match Response::from_bytes(&bytes[..]) {
+                Err(DecodeError::ChecksumMismatch { .. }) | Err(DecodeError::InvalidUtf8) => {}
+                other => panic!("corruption not detected: {:?}", other),
+            }
+        }
+        quickcheck(corrupted_frame_is_rejected as fn(String, usize, u8));
+
+        let mut bytes = Request::Search { word: "whale".into() }.to_bytes();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(matches!(
+            Request::from_bytes(&bytes[..]),
+            Err(DecodeError::ChecksumMismatch { .. })
+        ));
+        // A frame cut off before its checksum is an I/O error, not a mismatch
+        assert!(matches!(
+            Request::from_bytes(&bytes[..last - 2]),
+            Err(DecodeError::Io(_))
+        ));
+    }
+}
+
+// ============================ TRANSPORT ============================
+mod test_transport {
+    use ngram::transport::*;
+    #[test]
+    fn test_parse_address_5() {
+        assert_eq!(
+            "127.0.0.1:7878".parse::<Address>().unwrap(),
+            Address::Tcp("127.0.0.1:7878".parse().unwrap())
+        );
+        assert_eq!(
+            "[::1]:7878".parse::<Address>().unwrap(),
+            Address::Tcp("[::1]:7878".parse().unwrap())
+        );
+        assert!("localhost".parse::<Address>().is_err());
+        #[cfg(unix)]
+        {
+            let addr: Address = "unix:/tmp/ngram.sock".parse().unwrap();
+            assert_eq!(addr, Address::Unix("/tmp/ngram.sock".into()));
+            assert_eq!(addr.to_string(), "unix:/tmp/ngram.sock");
+        }
+    }
+}
+
+// ============================ DOC IDS ============================
+mod test_doc_id {
+    use super::*;
+    use ngram::message::*;
+    #[test]
+    fn test_index_round_trip_5() {
+        fn index_round_trip(n: usize) {
+            assert_eq!(DocId::from_index(n).unwrap().to_index(), Ok(n));
+        }
+        quickcheck(index_round_trip as fn(usize));
+    }
+    #[test]
+    fn test_oversized_id_is_rejected_5() {
+        let id = DocId(u64::MAX);
+        match usize::try_from(u64::MAX) {
+            Ok(n) => assert_eq!(id.to_index(), Ok(n)),
+            Err(_) => assert!(id.to_index().is_err()),
+        }
+    }
+}
+
+// ============================ DATABASE ============================
+mod test_database {
+    use ngram::database::*;
+    #[test]
+    fn test_text_range_char_boundaries_5() {
+        let doc = "añb€c";
+        assert_eq!(text_range(doc, 0, None), Some(0..doc.len()));
+        // The end is pulled back so we never split 'ñ'...
+        assert_eq!(text_range(doc, 0, Some(2)), Some(0..1));
+        // ...unless that would return nothing at all
+        assert_eq!(text_range(doc, 1, Some(1)), Some(1..3));
+        assert_eq!(text_range(doc, 1, Some(0)), Some(1..1));
+        // Offsets inside a character or past the end are rejected
+        assert_eq!(text_range(doc, 2, None), None);
+        assert_eq!(text_range(doc, doc.len() as u64, None), Some(doc.len()..doc.len()));
+        assert_eq!(text_range(doc, doc.len() as u64 + 1, None), None);
+    }
+
+    #[test]
+    fn test_analyzer_5() {
+        let doc = "Call me Ishmael. Some years ago...".to_string();
+
+        let exact = Database::new();
+        let id = exact.publish(doc.clone());
+        assert_eq!(exact.search("Ishmael."), vec![id]);
+        assert_eq!(exact.search("ishmael"), vec![]);
+
+        let analyzed = Database::with_options(
+            4,
+            Analyzer {
+                lowercase: true,
+                strip_punctuation: true,
+            },
+        );
+        let id = analyzed.publish(doc);
+        assert_eq!(analyzed.search("ishmael"), vec![id]);
+        assert_eq!(analyzed.search("ISHMAEL!"), vec![id]);
+        assert_eq!(analyzed.search("call"), vec![id]);
+        // A word that is all punctuation isn't indexed at all
+        assert_eq!(analyzed.search("..."), vec![]);
+    }
+
+    #[test]
+    fn test_cancellation_5() {
+        use ngram::cancel::{CancellationToken, Cancelled};
+        use std::time::{Duration, Instant};
+        let database = Database::with_options(1, Analyzer::default());
+        let doc = (0..5_000).map(|i| i.to_string()).collect::<Vec<_>>().join(" ");
+        let id = database.publish(doc + " whale");
+
+        let token = CancellationToken::new();
+        assert_eq!(database.search_cancellable("whale", &token), Ok(vec![id]));
+        let later = CancellationToken::with_deadline(Instant::now() + Duration::from_secs(60));
+        assert_eq!(database.search_cancellable("whale", &later), Ok(vec![id]));
+
+        let expired = CancellationToken::with_deadline(Instant::now());
+        assert_eq!(database.search_cancellable("whale", &expired), Err(Cancelled::DeadlinePassed));
+        let late = database.publish_cancellable("late".into(), &expired);
+        assert_eq!(late, Err(Cancelled::DeadlinePassed));
+
+        // Clones share the cancellation, which wins over a deadline that has also passed
+        let clone = expired.clone();
+        expired.cancel();
+        assert_eq!(clone.check(), Err(Cancelled::Requested));
+        assert!(clone.is_cancelled());
+        assert_eq!(database.document_count(), 1);
+    }
+
+    #[test]
+    fn test_publish_on_pool_5() {
+        use ngram::pool::ThreadPool;
+        let pool = ThreadPool::new(4);
+        let database = Database::new();
+
+        // Large enough to be indexed in several pieces, with words straddling where they'd be cut
+        let words = ["alpha", "beta", "gamma", "delta", "epsilon"];
+        let doc = (0..40_000)
+            .map(|i| words[i % words.len()])
+            .chain(["omega"])
+            .collect::<Vec<_>>()
+            .join(" ");
+        let id = database.publish_on(doc.clone(), &pool);
+
+        assert_eq!(database.retrieve(id), Some(doc));
+        for word in words.iter().chain(&["omega"]) {
+            assert_eq!(database.search(word), vec![id]);
         }
-        quickcheck(round_trip_response as fn(String, usize));
+        assert_eq!(database.search("alph"), vec![]);
+        assert_eq!(database.vocabulary_size(), 6);
+        assert_eq!(database.token_count(), 40_001);
+    }
+
+    #[test]
+    fn test_counts_5() {
+        let database = Database::with_options(4, Analyzer::default());
+        database.publish("the cat and the hat".to_string());
+        database.publish("a cat".to_string());
+        assert_eq!(database.document_count(), 2);
+        assert_eq!(database.vocabulary_size(), 5);
+        assert_eq!(database.token_count(), 7);
+    }
+}
+
+// ============================ LOGGING ============================
+mod test_logging {
+    use ngram::logging::LogFormat;
+    #[test]
+    fn test_parse_log_format_5() {
+        assert_eq!("text".parse(), Ok(LogFormat::Text));
+        assert_eq!("JSON".parse(), Ok(LogFormat::Json));
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+}
+
+// ============================ CONFIG ============================
+mod test_config {
+    use ngram::config::*;
+    use ngram::database::Analyzer;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn write_config(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("ngram-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_defaults_are_valid_5() {
+        let config = ServerConfig::builder().build().unwrap();
+        assert_eq!(config, ServerConfig::default());
+        assert_eq!(config.workers, 16);
+        assert_eq!(config.buckets, 128);
+    }
+
+    #[test]
+    fn test_file_then_overrides_5() {
+        let path = write_config(
+            "overrides",
+            r#"
+            workers = 4
+            buckets = 32
+            bind = "0.0.0.0"
+            port = 9000
+
+            [analyzer]
+            lowercase = true
+            "#,
+        );
+        let config = ServerConfig::builder()
+            .file(&path)
+            .unwrap()
+            .workers(2)
+            .build()
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.workers, 2);
+        assert_eq!(config.buckets, 32);
+        assert_eq!(config.bind.to_string(), "0.0.0.0");
+        assert_eq!(config.port, 9000);
+        assert_eq!(
+            config.analyzer,
+            Analyzer {
+                lowercase: true,
+                strip_punctuation: false
+            }
+        );
+        // Keys that weren't in the file keep their defaults
+        assert_eq!(config.limits, Limits::default());
+    }
+
+    #[test]
+    fn test_invalid_configs_are_rejected_5() {
+        assert!(matches!(
+            ServerConfig::builder().workers(0).build(),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            ServerConfig::builder().workers(4).max_workers(2).build(),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            ServerConfig::builder().buckets(0).build(),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            ServerConfig::builder().port(8000).http_port(8000).build(),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            ServerConfig::builder().data_dir("no/such/dir").build(),
+            Err(ConfigError::Invalid(_))
+        ));
+
+        let path = write_config("typo", "wrokers = 4\n");
+        let result = ServerConfig::builder().file(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(ConfigError::Parse { .. })));
+
+        assert!(matches!(
+            ServerConfig::builder().file("no/such/file.toml"),
+            Err(ConfigError::Io { .. })
+        ));
     }
 }
 
@@ -202,6 +1083,7 @@ mod integration {
     use ngram::message::*;
     use ngram::{client, server};
     use std::fs;
+    use std::net::SocketAddr;
     use std::sync::{Arc, Mutex};
     use std::thread::{self, JoinHandle};
     use std::time::Duration;
@@ -216,11 +1098,580 @@ mod integration {
         (server, handle)
     }
 
+    // Start a server on a port picked by the OS, returning the address it is listening on
+    fn start_server_on_any_port() -> (Arc<server::Server>, SocketAddr, JoinHandle<()>) {
+        let server = Arc::new(server::Server::new());
+        let handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run_on(("127.0.0.1", 0))
+        });
+        let addr = server.wait_for_addr(Duration::from_secs(5)).unwrap();
+        (server, addr, handle)
+    }
+
+    // Start a server configured by `config`, which should ask for port 0, returning the address it
+    // is listening on
+    fn start_server_with_config(
+        config: ngram::config::ServerConfig,
+    ) -> (Arc<server::Server>, SocketAddr, JoinHandle<()>) {
+        let server = Arc::new(server::Server::with_config(config));
+        let handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.serve().unwrap()
+        });
+        let addr = server.wait_for_addr(Duration::from_secs(5)).unwrap();
+        (server, addr, handle)
+    }
+
+    #[test]
+    fn test_port_zero_reports_bound_address_5() {
+        let (server, addr, handle) = start_server_on_any_port();
+        assert_ne!(addr.port(), 0);
+        assert_eq!(server.local_addr(), Some(addr));
+
+        let client = client::Client::from(addr);
+        assert_eq!(client.search("a"), Some(Response::SearchSuccess(vec![])));
+        server.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_bind_ipv6_5() {
+        // Not every test machine has IPv6 loopback configured
+        if std::net::TcpListener::bind("[::1]:0").is_err() {
+            return;
+        }
+        let server = Arc::new(server::Server::new());
+        let handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run_on(("::1", 0))
+        });
+        let addr = server.wait_for_addr(Duration::from_secs(5)).unwrap();
+        assert!(addr.is_ipv6());
+
+        let client = client::Client::new("::1", addr.port());
+        assert_eq!(client.search("a"), Some(Response::SearchSuccess(vec![])));
+        server.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_bind_failure_is_reported_5() {
+        let (server, addr, handle) = start_server_on_any_port();
+        let second = server::Server::new();
+        let (_, http) = second.start_http("127.0.0.1:0").unwrap();
+        // Binding the same address twice fails, and waiting for it doesn't hang
+        thread::scope(|scope| {
+            scope.spawn(|| second.run_on(addr));
+            assert_eq!(second.wait_for_addr(Duration::from_secs(5)), None);
+        });
+        // The server's other listeners stop with it
+        http.join().unwrap();
+        server.stop();
+        handle.join().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket_5() {
+        use ngram::transport::Address;
+        let dir = std::env::temp_dir().join(format!("ngram-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.sock");
+        // A stale socket file from a crashed server doesn't prevent binding
+        drop(std::os::unix::net::UnixListener::bind(&path));
+
+        let (server, addr, handle) = start_server_on_any_port();
+        let unix_handle = server.start_unix(&path).unwrap();
+
+        // A second server can't take over a socket that is still being served
+        assert!(server::Server::new().start_unix(&path).is_err());
+
+        let unix_client = client::Client::new(&format!("unix:{}", path.display()), 0);
+        let id = match unix_client.publish_from_path("data/blake-poems.txt") {
+            Some(Response::PublishSuccess(id)) => id,
+            _ => panic!("Failed to publish over the unix socket"),
+        };
+        // Both transports share the same database
+        let tcp_client = client::Client::from(addr);
+        assert_eq!(tcp_client.search("Lamb"), Some(Response::SearchSuccess(vec![id])));
+        let unix_client = client::Client::from(Address::Unix(path.clone()));
+        assert_eq!(unix_client.search("Lamb"), Some(Response::SearchSuccess(vec![id])));
+
+        server.stop();
+        handle.join().unwrap();
+        unix_handle.join().unwrap();
+        assert!(!path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_server_from_config_5() {
+        use ngram::config::ServerConfig;
+        use std::io::{BufRead, BufReader, Read, Write};
+        let dir = std::env::temp_dir().join(format!("ngram-data-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.txt"), "The whale").unwrap();
+        fs::write(dir.join("b.txt"), "the WHALE, again").unwrap();
+
+        let config = ServerConfig::builder()
+            .port(0)
+            .workers(2)
+            .buckets(8)
+            .data_dir(&dir)
+            .max_document_bytes(64)
+            .analyzer(ngram::database::Analyzer {
+                lowercase: true,
+                strip_punctuation: true,
+            })
+            .build()
+            .unwrap();
+        let (server, addr, handle) = start_server_with_config(config);
+        let client = client::Client::from(addr);
+
+        // The data directory is loaded in the background; wait until the server says it's done
+        while client.ready() != Some(Response::Ready(true)) {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // Files are loaded in name order, and indexed with the configured analyzer
+        assert_eq!(
+            client.search("Whale"),
+            Some(Response::SearchSuccess(vec![DocId(0), DocId(1)]))
+        );
+        assert_eq!(
+            client.retrieve(DocId(1)),
+            Some(Response::RetrieveSuccess("the WHALE, again".into()))
+        );
+
+        // Documents over the configured limit are refused
+        let path = dir.join("big.txt");
+        fs::write(&path, "x".repeat(65)).unwrap();
+        assert_eq!(
+            client.publish_from_path(path.to_str().unwrap()),
+            Some(Response::Failure)
+        );
+        // ... as soon as too much has arrived, without waiting for the rest
+        let mut binary = std::net::TcpStream::connect(addr).unwrap();
+        binary.write_all(&[0, 0xff, 0xff, 0xff, 0xff]).unwrap();
+        binary.write_all(&[b'x'; 2048]).unwrap();
+        let mut response = Vec::new();
+        binary.read_to_end(&mut response).unwrap();
+        assert_eq!(Response::from_bytes(&response[..]).unwrap(), Response::Failure);
+        let mut json = std::net::TcpStream::connect(addr).unwrap();
+        json.write_all(b"{\"Publish\":{\"doc\":\"").unwrap();
+        json.write_all(&[b'x'; 2048]).unwrap();
+        let mut response = String::new();
+        BufReader::new(json).read_line(&mut response).unwrap();
+        assert_eq!(response, "\"Failure\"\n");
+
+        server.stop();
+        handle.join().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_idle_connections_time_out_5() {
+        use ngram::config::ServerConfig;
+        use std::io::Read;
+        use std::net::TcpStream;
+
+        let config = ServerConfig::builder()
+            .port(0)
+            .workers(2)
+            .idle_timeout(Some(Duration::from_millis(200)))
+            .build()
+            .unwrap();
+        let (server, addr, handle) = start_server_with_config(config);
+
+        // More silent connections than there are workers
+        let mut idle = (0..4)
+            .map(|_| TcpStream::connect(addr).unwrap())
+            .collect::<Vec<_>>();
+        let started = std::time::Instant::now();
+        let client = client::Client::from(addr);
+        assert_eq!(client.search("a"), Some(Response::SearchSuccess(vec![])));
+        assert!(started.elapsed() < Duration::from_secs(3));
+
+        // The server hung up on each of them without answering
+        for stream in idle.iter_mut() {
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut buf = Vec::new();
+            assert_eq!(stream.read_to_end(&mut buf).unwrap(), 0);
+        }
+        server.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_max_connections_5() {
+        use ngram::config::ServerConfig;
+        use std::net::TcpStream;
+
+        let config = ServerConfig::builder()
+            .port(0)
+            .max_connections(2)
+            .build()
+            .unwrap();
+        let (server, addr, handle) = start_server_with_config(config);
+
+        let idle = (0..2)
+            .map(|_| TcpStream::connect(addr).unwrap())
+            .collect::<Vec<_>>();
+        thread::sleep(Duration::from_millis(200));
+
+        // A connection over the cap is told to come back later
+        let client = client::Client::from(addr);
+        assert_eq!(
+            client.search("a"),
+            Some(Response::Error(ErrorCode::Overloaded))
+        );
+        assert_eq!(server.shed_count(), 1);
+
+        // Once the idle clients leave, new connections are served again
+        drop(idle);
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(client.search("a"), Some(Response::SearchSuccess(vec![])));
+        server.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_full_queue_sheds_connections_5() {
+        use ngram::config::ServerConfig;
+        use std::net::TcpStream;
+
+        let config = ServerConfig::builder()
+            .port(0)
+            .workers(1)
+            .queue_capacity(1)
+            .build()
+            .unwrap();
+        let (server, addr, handle) = start_server_with_config(config);
+
+        // One silent client holds the only worker and another waits in the queue
+        let busy = TcpStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        let queued = TcpStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        let client = client::Client::from(addr);
+        assert_eq!(
+            client.search("a"),
+            Some(Response::Error(ErrorCode::Overloaded))
+        );
+        assert_eq!(server.shed_count(), 1);
+
+        // Once they hang up there is room again
+        drop((busy, queued));
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(client.search("a"), Some(Response::SearchSuccess(vec![])));
+        server.stop();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_stats_5() {
+        let (server, addr, handle) = start_server_on_any_port();
+        let (http_addr, _) = server.start_http(("127.0.0.1", 0)).unwrap();
+        let client = client::Client::from(addr);
+        let dir = std::env::temp_dir().join(format!("ngram-stats-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("doc.txt");
+        fs::write(&path, "one two two three").unwrap();
+        client.publish_from_path(path.to_str().unwrap());
+        client.search("two");
+        client.search("four");
+        client.retrieve(DocId(5));
+
+        let stats = match client.stats() {
+            Some(Response::Stats(stats)) => stats,
+            response => panic!("unexpected response {:?}", response),
+        };
+        assert_eq!(stats.documents, 1);
+        assert_eq!(stats.vocabulary, 3);
+        assert_eq!(stats.total_tokens, 4);
+        assert_eq!(stats.queue_depth, 0);
+        // The stats request itself is being handled
+        assert_eq!(stats.active_workers, 1);
+        let counts = stats
+            .requests
+            .iter()
+            .map(|r| (r.kind.as_str(), r.count, r.failures))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            counts,
+            vec![
+                ("publish", 1, 0),
+                ("search", 2, 0),
+                ("retrieve", 1, 1),
+                ("retrieve_stream", 0, 0),
+                ("stats", 0, 0),
+                ("admin", 0, 0),
+            ]
+        );
+        let search = &stats.requests[1];
+        assert_eq!(search.latency_buckets.iter().sum::<u64>(), 2);
+
+        let (status, body) = http_request(http_addr.port(), "GET /metrics HTTP/1.1\r\n\r\n");
+        assert_eq!(status, 200);
+        assert!(body.contains("ngram_documents 1\n"));
+        assert!(body.contains("ngram_requests_total{type=\"search\"} 2\n"));
+        assert!(body.contains("ngram_request_duration_seconds_count{type=\"stats\"} 1\n"));
+        assert!(body.contains(
+            "ngram_request_duration_seconds_bucket{type=\"search\",le=\"+Inf\"} 2\n"
+        ));
+
+        server.stop();
+        handle.join().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_slow_query_log_5() {
+        use ngram::config::ServerConfig;
+        let dir = std::env::temp_dir().join(format!("ngram-slow-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let log_path = dir.join("slow.log");
+
+        // With no threshold every request counts as slow
+        let config = ServerConfig::builder()
+            .port(0)
+            .slow_query_threshold(Duration::ZERO)
+            .slow_query_log_path(&log_path)
+            .slow_queries_kept(2)
+            .admin_token("secret")
+            .build()
+            .unwrap();
+        let (server, addr, handle) = start_server_with_config(config);
+        let client = client::Client::from(addr).with_admin_token("secret");
+
+        let doc_path = dir.join("doc.txt");
+        fs::write(&doc_path, "a b a").unwrap();
+        client.publish_from_path(doc_path.to_str().unwrap());
+        client.search("a");
+        client.retrieve(DocId(0));
+
+        // Only the most recent ones are kept, newest first
+        let queries = match client.slow_queries(10) {
+            Some(Response::SlowQueries(queries)) => queries,
+            response => panic!("unexpected response {:?}", response),
+        };
+        let summaries = queries
+            .iter()
+            .map(|q| (q.summary.as_str(), q.result_size))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summaries,
+            vec![("retrieve 0 from 0", 5), ("search \"a\"", 1)]
+        );
+        match client.slow_queries(1) {
+            Some(Response::SlowQueries(queries)) => assert_eq!(queries.len(), 1),
+            response => panic!("unexpected response {:?}", response),
+        }
+
+        // Every one of them was also written to the file
+        let log = fs::read_to_string(&log_path).unwrap();
+        let lines = log.lines().collect::<Vec<_>>();
+        assert!(lines.len() >= 3);
+        assert!(lines[0].contains("\"summary\":\"publish 5 bytes\""));
+
+        server.stop();
+        handle.join().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_admin_requests_5() {
+        use ngram::config::ServerConfig;
+        let config = ServerConfig::builder()
+            .port(0)
+            .admin_token("secret")
+            .build()
+            .unwrap();
+        let (_server, addr, handle) = start_server_with_config(config);
+        let client = client::Client::from(addr);
+
+        // Health and readiness checks don't need the token
+        match client.health() {
+            Some(Response::Health(health)) => assert_eq!(health.in_flight, 1),
+            response => panic!("unexpected response {:?}", response),
+        }
+        assert_eq!(client.ready(), Some(Response::Ready(true)));
+
+        // Everything else does
+        let unauthorized = Some(Response::Error(ErrorCode::Unauthorized));
+        assert_eq!(client.shutdown(true), unauthorized);
+        let wrong = client::Client::from(addr).with_admin_token("wrong");
+        assert_eq!(wrong.flush(), unauthorized);
+        assert_eq!(client.slow_queries(1), unauthorized);
+
+        let admin = client.with_admin_token("secret");
+        assert_eq!(admin.flush(), Some(Response::Ok));
+        assert_eq!(admin.shutdown(true), Some(Response::Ok));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_request_deadlines_5() {
+        let (server, addr, handle) = start_server_on_any_port();
+        let client = client::Client::from(addr);
+        let Some(Response::PublishSuccess(id)) = client.publish_from_path("Cargo.toml") else {
+            panic!("publish failed");
+        };
+
+        // A deadline that has passed by the time the request is read can't be met
+        let hurried = client::Client::from(addr).with_timeout(Duration::ZERO);
+        let timeout = Some(Response::Error(ErrorCode::Timeout));
+        assert_eq!(hurried.search("ngram"), timeout);
+        assert_eq!(hurried.retrieve(id), timeout);
+        assert_eq!(hurried.retrieve_to_writer(id, 0, 16, Vec::new()), None);
+
+        // One that leaves enough time changes nothing
+        let patient = client::Client::from(addr).with_timeout(Duration::from_secs(30));
+        assert_eq!(patient.search("[package]"), Some(Response::SearchSuccess(vec![id])));
+        assert!(patient.retrieve_to_writer(id, 0, 16, Vec::new()).is_some());
+
+        // Nested deadlines are refused by the binary decoder
+        let nested = Request::Deadline {
+            timeout_ms: 1,
+            request: Box::new(Request::Stats.with_timeout(Duration::from_secs(1))),
+        };
+        assert!(Request::from_bytes(&nested.to_bytes()[..]).is_err());
+        assert_eq!(nested.into_parts(), (Request::Stats, Some(Duration::from_millis(1))));
+
+        server.stop();
+        handle.join().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hang_up_cancels_request_5() {
+        use ngram::config::ServerConfig;
+        use ngram::transport::{Address, Stream};
+        use std::io::{Read, Write};
+        use std::net::Shutdown;
+        let config = ServerConfig::builder().port(0).workers(1).build().unwrap();
+        let (server, addr, handle) = start_server_with_config(config);
+        let tmp = std::env::temp_dir();
+        let path = tmp.join(format!("ngram-hang-up-{}.sock", std::process::id()));
+        let unix_handle = server.start_unix(&path).unwrap();
+        let unix = Address::Unix(path.clone());
+
+        let doc_path = tmp.join(format!("ngram-hang-up-{}.txt", std::process::id()));
+        fs::write(&doc_path, "whale ".repeat(1 << 20)).unwrap();
+        let client = client::Client::from(addr);
+        let id = match client.publish_from_path(doc_path.to_str().unwrap()) {
+            Some(Response::PublishSuccess(id)) => id,
+            response => panic!("unexpected response {:?}", response),
+        };
+        fs::remove_file(&doc_path).unwrap();
+
+        // Keep the only worker busy streaming to a client that doesn't read
+        let mut busy = Stream::connect(&unix).unwrap();
+        let stream = Request::RetrieveStream {
+            id,
+            offset: 0,
+            chunk_size: 1 << 16,
+        };
+        busy.write_all(&stream.to_bytes()).unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        // A client that hangs up while its search waits for the worker gets no answer, and the
+        // search is given up on
+        let mut gone = Stream::connect(&unix).unwrap();
+        gone.write_all(&Request::Search { word: "whale".into() }.to_bytes()).unwrap();
+        drop(gone);
+        // One that only stops sending still gets its answer
+        let mut waiting = Stream::connect(&unix).unwrap();
+        waiting.write_all(&Request::Search { word: "whale".into() }.to_bytes()).unwrap();
+        waiting.shutdown(Shutdown::Write).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        drop(busy);
+
+        let mut answer = Vec::new();
+        waiting.read_to_end(&mut answer).unwrap();
+        let answer = Response::from_bytes(&answer[..]).unwrap();
+        assert_eq!(answer, Response::SearchSuccess(vec![id]));
+        let stats = match client.stats() {
+            Some(Response::Stats(stats)) => stats,
+            response => panic!("unexpected response {:?}", response),
+        };
+        let search = stats.requests.iter().find(|r| r.kind == "search").unwrap();
+        assert_eq!((search.count, search.failures), (2, 1));
+
+        server.stop();
+        handle.join().unwrap();
+        unix_handle.join().unwrap();
+    }
+
     #[test]
     fn test_start_stop_server_5() {
         let port = 7880;
-        let (server, _handle) = start_server(port);
+        let (server, handle) = start_server(port);
+        let stopped = std::time::Instant::now();
+        server.stop();
+        // `run` must return promptly even though no connection arrives to wake the listener
+        handle.join().unwrap();
+        assert!(stopped.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_stop_drains_in_flight_requests_5() {
+        use std::io::{Read, Write};
+        let port = 7894;
+        let server = Arc::new(server::Server::new().with_shutdown_timeout(Duration::from_secs(5)));
+        let handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+
+        // Start a request but don't finish sending it before the server is stopped
+        let request = Request::Search { word: "a".into() }.to_bytes();
+        let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(&request[..3]).unwrap();
+        thread::sleep(Duration::from_millis(200));
         server.stop();
+        thread::sleep(Duration::from_millis(200));
+        assert!(!handle.is_finished());
+
+        // The in-flight request is still answered, and then `run` returns
+        stream.write_all(&request[3..]).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        assert_eq!(
+            Response::from_bytes(&response[..]).unwrap(),
+            Response::SearchSuccess(vec![])
+        );
+        handle.join().unwrap();
+
+        // New connections are refused once the listener is gone
+        assert!(std::net::TcpStream::connect(("127.0.0.1", port)).is_err());
+    }
+
+    #[test]
+    fn test_shutdown_timeout_5() {
+        let port = 7895;
+        let server =
+            Arc::new(server::Server::new().with_shutdown_timeout(Duration::from_millis(300)));
+        let handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+
+        // A client that never sends its request can't hold up shutdown past the deadline
+        let stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        let stopped = std::time::Instant::now();
+        server.stop();
+        handle.join().unwrap();
+        let elapsed = stopped.elapsed();
+        assert!(elapsed >= Duration::from_millis(300) && elapsed < Duration::from_secs(3));
+        drop(stream);
     }
 
     #[test]
@@ -324,6 +1775,177 @@ mod integration {
         server.stop();
     }
 
+    #[test]
+    fn test_retrieve_out_of_range_id_5() {
+        let port = 7890;
+        let (server, _handle) = start_server(port);
+
+        let client = client::Client::new("127.0.0.1", port);
+        assert_eq!(client.retrieve(DocId(u64::MAX)), Some(Response::Failure));
+        assert_eq!(client.retrieve(DocId(1 << 40)), Some(Response::Failure));
+        server.stop();
+    }
+
+    #[test]
+    fn test_retrieve_range_5() {
+        let port = 7887;
+        let (server, _handle) = start_server(port);
+
+        let client = client::Client::new("127.0.0.1", port);
+        let id = match client.publish_from_path("data/austen-emma.txt") {
+            Some(Response::PublishSuccess(id)) => id,
+            _ => panic!("Failed to publish data/austen-emma.txt"),
+        };
+        let doc = std::fs::read_to_string("data/austen-emma.txt").unwrap();
+
+        // Page through the document and check the pieces add back up to the original
+        let mut paged = String::new();
+        while paged.len() < doc.len() {
+            match client.retrieve_range(id, paged.len() as u64, Some(100_000)) {
+                Some(Response::RetrieveSuccess(part)) => {
+                    assert!(!part.is_empty());
+                    paged.push_str(&part);
+                }
+                other => panic!("Unexpected response {:?}", other),
+            }
+        }
+        assert_eq!(paged, doc);
+
+        let response = client.retrieve_range(id, doc.len() as u64 + 1, None);
+        assert_eq!(response, Some(Response::Failure));
+        server.stop();
+    }
+
+    #[test]
+    fn test_retrieve_stream_5() {
+        let port = 7888;
+        let (server, _handle) = start_server(port);
+
+        let client = client::Client::new("127.0.0.1", port);
+        let id = match client.publish_from_path("data/austen-emma.txt") {
+            Some(Response::PublishSuccess(id)) => id,
+            _ => panic!("Failed to publish data/austen-emma.txt"),
+        };
+        let doc = std::fs::read_to_string("data/austen-emma.txt").unwrap();
+
+        let mut streamed = Vec::new();
+        let written = client.retrieve_to_writer(id, 0, 4096, &mut streamed);
+        assert_eq!(written, Some(doc.len() as u64));
+        assert_eq!(String::from_utf8(streamed).unwrap(), doc);
+
+        assert_eq!(client.retrieve_to_writer(DocId(id.0 + 1), 0, 4096, Vec::new()), None);
+        server.stop();
+    }
+
+    #[test]
+    fn test_json_codec_5() {
+        use std::io::{BufRead, BufReader, Write};
+        let port = 7891;
+        let (server, _handle) = start_server(port);
+
+        let client = client::Client::new("127.0.0.1", port).with_codec(&JsonCodec);
+        let id = match client.publish_from_path("data/blake-poems.txt") {
+            Some(Response::PublishSuccess(id)) => id,
+            _ => panic!("Failed to publish data/blake-poems.txt"),
+        };
+
+        // A hand-written request, as a script or netcat would send it
+        let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(b"{\"Search\":{\"word\":\"Lamb\"}}\n").unwrap();
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        assert_eq!(line, format!("{{\"SearchSuccess\":[{}]}}\n", id));
+
+        // Binary clients are unaffected on the same port
+        let client = client::Client::new("127.0.0.1", port);
+        assert_eq!(client.search("Lamb"), Some(Response::SearchSuccess(vec![id])));
+        server.stop();
+    }
+
+    // Send a raw HTTP request and return the status code and the body
+    fn http_request(port: u16, request: &str) -> (u16, String) {
+        use std::io::{Read, Write};
+        let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+        (status, body.to_string())
+    }
+
+    #[test]
+    fn test_http_gateway_5() {
+        let port = 7892;
+        let http_port = 7893;
+        let (server, _handle) = start_server(port);
+        server.start_http(("127.0.0.1", http_port)).unwrap();
+
+        let body = r#"{"doc": "the quick brown fox"}"#;
+        let (status, response) = http_request(
+            http_port,
+            &format!(
+                "POST /documents HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            ),
+        );
+        assert_eq!((status, response.as_str()), (201, "{\"id\":0}\n"));
+
+        let (status, response) = http_request(http_port, "GET /search?q=quick HTTP/1.1\r\n\r\n");
+        assert_eq!((status, response.as_str()), (200, "{\"ids\":[0]}\n"));
+
+        let (status, response) =
+            http_request(http_port, "GET /documents/0?offset=4&len=5 HTTP/1.1\r\n\r\n");
+        assert_eq!((status, response.as_str()), (200, "{\"doc\":\"quick\",\"id\":0}\n"));
+
+        let (status, _) = http_request(http_port, "GET /documents/7 HTTP/1.1\r\n\r\n");
+        assert_eq!(status, 404);
+        let (status, _) = http_request(http_port, "DELETE /documents/0 HTTP/1.1\r\n\r\n");
+        assert_eq!(status, 405);
+        let (status, _) = http_request(http_port, "GET /search HTTP/1.1\r\n\r\n");
+        assert_eq!(status, 400);
+
+        // Documents published over HTTP are visible to binary clients and vice versa
+        let client = client::Client::new("127.0.0.1", port);
+        assert_eq!(
+            client.search("fox"),
+            Some(Response::SearchSuccess(vec![DocId(0)]))
+        );
+        server.stop();
+    }
+
+    #[test]
+    fn test_http_slow_query_log_5() {
+        use ngram::config::ServerConfig;
+        let config = ServerConfig::builder()
+            .port(0)
+            .slow_query_threshold(Duration::ZERO)
+            .admin_token("secret")
+            .build()
+            .unwrap();
+        let (server, addr, handle) = start_server_with_config(config);
+        let (http_addr, _http_handle) = server.start_http("127.0.0.1:0").unwrap();
+
+        let (status, _) = http_request(http_addr.port(), "GET /search?q=fox HTTP/1.1\r\n\r\n");
+        assert_eq!(status, 200);
+
+        // Searches over HTTP are logged like any other
+        let client = client::Client::from(addr).with_admin_token("secret");
+        let queries = match client.slow_queries(10) {
+            Some(Response::SlowQueries(queries)) => queries,
+            response => panic!("unexpected response {:?}", response),
+        };
+        let summaries = queries
+            .iter()
+            .map(|q| (q.kind.as_str(), q.summary.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(summaries, vec![("search", "search \"fox\"")]);
+
+        server.stop();
+        handle.join().unwrap();
+    }
+
     #[test]
     fn test_server_stress_test_10() {
         let port = 7889;